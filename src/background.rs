@@ -1,20 +1,22 @@
 use crate::commands::handle_get_threats_info;
 use crate::{
-    handle_connect_domain, handle_get_core_info, handle_get_core_version, handle_lanscan, State,
+    clear_memory_access_events, describe_memory_access, detect_memory_access, diff_lan_devices,
+    handle_connect_domain, handle_get_core_info, handle_get_core_version, prepare_lan_scan,
+    read_memory_access_events, record_lan_events, record_memory_access, run_lan_scan,
+    sample_egress, wait_for_phases, EgressLog, GatewayOptions, InstanceLock, LanDisplayOptions,
+    LanInventory, NetworkSelection, ScanOptions, ScanProfile, State, WaitOptions, WaitPhase,
 };
 #[cfg(unix)]
-use daemonize::Daemonize;
+use daemonize::{Daemonize, Outcome};
 use edamame_core::api::api_core::{disconnect_domain, get_connection, set_credentials};
-use edamame_core::api::api_lanscan::{
-    get_lan_devices, get_last_gateway_scan, grant_consent, set_network, LANScanAPINetwork,
-};
 use edamame_core::api::api_score::compute_score;
+use std::collections::HashSet;
 #[cfg(unix)]
 use std::process::Command as ProcessCommand;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use sysinfo::{Pid, System};
-use tracing::info;
+use tracing::{error, info};
 #[cfg(windows)]
 use widestring::U16CString;
 #[cfg(windows)]
@@ -24,14 +26,266 @@ use windows::Win32::Foundation::{CloseHandle, HANDLE};
 #[cfg(windows)]
 use windows::Win32::System::Threading::*;
 
-pub fn background_process(user: String, domain: String, pin: String, lan_scanning: bool) {
+// Parameters of the background process, passed on its command line and saved in the state
+#[derive(Clone, Debug)]
+pub struct BackgroundParams {
+    pub user: String,
+    pub domain: String,
+    pub pin: String,
+    pub device_id: String,
+    pub lan_scanning: bool,
+    // Seconds before stopping by itself, 0 for no limit
+    pub max_duration: u64,
+    // Process whose exit stops the background process
+    pub parent_pid: Option<u32>,
+    pub network: NetworkSelection,
+    pub gateway: GatewayOptions,
+    // Seconds between two LAN monitoring scans, 0 to scan only once
+    pub lan_monitor_interval: u64,
+    pub scan: ScanOptions,
+    // Seconds between two egress samples, 0 to disable the egress monitor
+    pub egress_interval: u64,
+    // Watch for processes reading the memory of other processes
+    pub memory_watch: bool,
+}
+
+impl BackgroundParams {
+    pub fn to_args(&self) -> Vec<String> {
+        vec![
+            self.user.clone(),
+            self.domain.clone(),
+            self.pin.clone(),
+            self.device_id.clone(),
+            self.lan_scanning.to_string(),
+            self.max_duration.to_string(),
+            self.parent_pid.unwrap_or(0).to_string(),
+        ]
+        .into_iter()
+        .chain(self.network.to_args())
+        .chain(self.gateway.to_args())
+        .chain([self.lan_monitor_interval.to_string()])
+        .chain(self.scan.to_args())
+        .chain([
+            self.egress_interval.to_string(),
+            self.memory_watch.to_string(),
+        ])
+        .collect()
+    }
+
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        if args.len() != 20 {
+            return None;
+        }
+        Some(BackgroundParams {
+            user: args[0].clone(),
+            domain: args[1].clone(),
+            pin: args[2].clone(),
+            device_id: args[3].clone(),
+            lan_scanning: args[4] == "true",
+            max_duration: args[5].parse().ok()?,
+            parent_pid: match args[6].parse().ok()? {
+                0 => None,
+                pid => Some(pid),
+            },
+            network: NetworkSelection::from_args(&args[7..11])?,
+            gateway: GatewayOptions::from_args(&args[11..13])?,
+            lan_monitor_interval: args[13].parse().ok()?,
+            scan: ScanOptions::from_args(&args[14..18])?,
+            egress_interval: args[18].parse().ok()?,
+            memory_watch: args[19] == "true",
+        })
+    }
+
+    pub fn from_state(state: &State, pin: String) -> Self {
+        BackgroundParams {
+            user: state.connected_user.clone(),
+            domain: state.connected_domain.clone(),
+            pin,
+            device_id: state.device_id.clone(),
+            lan_scanning: state.lan_scanning,
+            max_duration: state.max_duration,
+            parent_pid: state.parent_pid,
+            network: state.network.clone(),
+            gateway: state.gateway.clone(),
+            lan_monitor_interval: state.lan_monitor_interval,
+            scan: state.scan.clone(),
+            egress_interval: state.egress_interval,
+            memory_watch: state.memory_watch,
+        }
+    }
+
+    pub fn to_state(&self, pid: u32, handle: Option<u64>) -> State {
+        State {
+            pid: Some(pid),
+            handle,
+            is_success: false,
+            connected_domain: self.domain.clone(),
+            connected_user: self.user.clone(),
+            last_network_activity: "".to_string(),
+            start_time: process_start_time(pid),
+            device_id: self.device_id.clone(),
+            lan_scanning: self.lan_scanning,
+            max_duration: self.max_duration,
+            parent_pid: self.parent_pid,
+            network: self.network.clone(),
+            gateway: self.gateway.clone(),
+            gateway_outcome: "".to_string(),
+            lan_monitor_interval: self.lan_monitor_interval,
+            scan: self.scan.clone(),
+            egress_interval: self.egress_interval,
+            memory_watch: self.memory_watch,
+            backend_error_code: "".to_string(),
+            last_lan_scan: "".to_string(),
+        }
+    }
+}
+
+// Stop the background process when its maximum duration is reached or the watched process exits
+fn spawn_lifetime_watchdog(max_duration: u64, parent_pid: Option<u32>) {
+    if max_duration == 0 && parent_pid.is_none() {
+        return;
+    }
+    let started = Instant::now();
+    // Remember the start time of the watched process to detect PID reuse
+    let parent = parent_pid.map(|pid| (pid, process_start_time(pid)));
+    std::thread::spawn(move || loop {
+        let mut reason = None;
+        if max_duration > 0 && started.elapsed() >= Duration::from_secs(max_duration) {
+            reason = Some(format!("maximum duration of {}s reached", max_duration));
+        }
+        if let Some((pid, start_time)) = parent {
+            let mut system = System::new();
+            let alive = system.refresh_process(Pid::from_u32(pid))
+                && system
+                    .process(Pid::from_u32(pid))
+                    .map(|process| start_time == 0 || process.start_time() == start_time)
+                    .unwrap_or(false);
+            if !alive {
+                reason = Some(format!("watched process ({}) exited", pid));
+            }
+        }
+        if let Some(reason) = reason {
+            info!("Stopping background process: {}", reason);
+            disconnect_domain();
+            State::clear();
+            std::process::exit(0);
+        }
+        sleep(Duration::from_secs(1));
+    });
+}
+
+// Rescan the LAN periodically and record the changes as events
+fn spawn_lan_monitor(
+    interval: u64,
+    network: NetworkSelection,
+    mut scan: ScanOptions,
+    inventory: LanInventory,
+) {
+    if interval == 0 {
+        return;
+    }
+    // Bound each monitoring scan by the monitoring interval
+    if scan.max_duration == 0 {
+        scan.max_duration = interval.max(60);
+    }
+    let mut previous = inventory;
+    std::thread::spawn(move || loop {
+        sleep(Duration::from_secs(interval));
+        info!("Requesting a LAN monitoring scan...");
+        let current = match run_lan_scan(&network, &scan, &LanDisplayOptions::default()) {
+            Ok(current) => current,
+            Err(e) => {
+                error!("LAN monitoring scan failed: {}", e);
+                continue;
+            }
+        };
+        // The core only updates the scan date once a full scan has completed
+        if scan.profile == ScanProfile::Full && current.last_scan == previous.last_scan {
+            info!("LAN monitoring scan did not complete in time");
+            continue;
+        }
+        record_lan_events(&diff_lan_devices(&previous.devices, &current.devices));
+        previous = current;
+    });
+}
+
+// Sample the outbound connections and record the destinations
+fn spawn_egress_monitor(interval: u64) {
+    if interval == 0 {
+        return;
+    }
+    if !cfg!(target_os = "linux") {
+        error!("The egress monitor is only supported on Linux");
+        return;
+    }
+    // Each run of the background process starts a new log
+    let mut log = EgressLog::new();
+    log.save();
+    let mut last_save = Instant::now();
+    std::thread::spawn(move || loop {
+        let changed = sample_egress(&mut log);
+        // Refresh the last seen dates from time to time
+        if changed || last_save.elapsed() >= Duration::from_secs(60) {
+            log.save();
+            last_save = Instant::now();
+        }
+        sleep(Duration::from_secs(interval));
+    });
+}
+
+// Record the processes reading the memory of other processes, such as the runner worker
+fn spawn_memory_watch(enabled: bool) {
+    if !enabled {
+        return;
+    }
+    if !cfg!(target_os = "linux") {
+        error!("The memory access watch is only supported on Linux");
+        return;
+    }
+    clear_memory_access_events();
+    let mut known = HashSet::new();
+    std::thread::spawn(move || loop {
+        let new_accesses: Vec<_> = detect_memory_access()
+            .into_iter()
+            .filter(|access| {
+                known.insert((access.kind.clone(), access.accessor_pid, access.target_pid))
+            })
+            .collect();
+        record_memory_access(&new_accesses);
+        sleep(Duration::from_secs(2));
+    });
+}
+
+pub fn background_process(params: BackgroundParams) {
+    let BackgroundParams {
+        user,
+        domain,
+        pin,
+        lan_scanning,
+        max_duration,
+        parent_pid,
+        network,
+        gateway,
+        lan_monitor_interval,
+        scan,
+        egress_interval,
+        memory_watch,
+        ..
+    } = params;
     info!(
-        "Starting background process with user: {}, domain: {}, lan_scanning: {}",
-        user, domain, lan_scanning
+        "Starting background process with user: {}, domain: {}, lan_scanning: {}, max_duration: {}, parent_pid: {:?}",
+        user, domain, lan_scanning, max_duration, parent_pid
     );
 
     // We are using the logger as we are in the background process
 
+    // Enforce the lifetime limits during the whole run, including the initial scans
+    spawn_lifetime_watchdog(max_duration, parent_pid);
+
+    // Record the outbound connections during the whole run
+    spawn_egress_monitor(egress_interval);
+    spawn_memory_watch(memory_watch);
+
     // Show threats info
     handle_get_threats_info();
 
@@ -43,42 +297,34 @@ pub fn background_process(user: String, domain: String, pin: String, lan_scannin
     if lan_scanning {
         info!("Scanning network interfaces...");
 
-        // Initialize network to autodetect
-        set_network(LANScanAPINetwork {
-            interfaces: vec![],
-            scanned_interfaces: vec![],
-            is_ethernet: true,
-            is_wifi: false,
-            is_vpn: false,
-            is_tethering: false,
-            is_mobile: false,
-            wifi_bssid: "".to_string(),
-            wifi_ip: "".to_string(),
-            wifi_submask: "".to_string(),
-            wifi_gateway: "".to_string(),
-            wifi_broadcast: "".to_string(),
-            wifi_name: "".to_string(),
-            wifi_ipv6: "".to_string(),
-        });
-
-        // Grant consent
-        grant_consent();
-
-        // Wait for the gateway detection to complete
-        let mut last_gateway_scan = get_last_gateway_scan();
-        while last_gateway_scan == "" {
-            info!("Waiting for gateway detection to complete...");
-            sleep(Duration::from_secs(5));
-            last_gateway_scan = get_last_gateway_scan();
+        // Initialize network, autodetected unless interfaces or subnets were selected
+        match prepare_lan_scan(&network, &gateway) {
+            Ok(outcome) => {
+                info!("{}, requesting a LAN scan...", outcome);
+                State::update(|state| state.gateway_outcome = outcome.to_string());
+            }
+            Err(e) => {
+                error!("Invalid network selection: {}", e);
+                // Exit with an error code
+                std::process::exit(1);
+            }
         }
 
-        info!("Gateway detection complete, requesting a LAN scan...");
+        // Scan with the selected profile and wait for the scan to complete
+        let inventory = match run_lan_scan(&network, &scan, &LanDisplayOptions::default()) {
+            Ok(inventory) => inventory,
+            Err(e) => {
+                error!("LAN scan failed: {}", e);
+                // Exit with an error code
+                std::process::exit(1);
+            }
+        };
 
-        // Request a LAN scan
-        _ = get_lan_devices(true, false, false);
+        // Let the waiters know the scan is done
+        State::update(|state| state.last_lan_scan = inventory.last_scan.clone());
 
-        // Wait for the scan to complete
-        handle_lanscan(true);
+        // Keep monitoring the LAN
+        spawn_lan_monitor(lan_monitor_interval, network, scan, inventory);
     }
 
     // Request immediate score computation
@@ -89,44 +335,126 @@ pub fn background_process(user: String, domain: String, pin: String, lan_scannin
     info!("Connecting to domain...");
     handle_connect_domain();
 
-    // Loop forever as background process is running, write the shared state based on the connection status
+    // Loop forever as background process is running, write the shared state when the connection status changes
+    let mut last_status = None;
     loop {
-        let connection_status = get_connection();
-        let mut state = State::load();
-        state.is_success = connection_status.is_success;
-        state.last_network_activity = connection_status.last_network_activity;
-        state.save();
-
-        // Exit if there are no pid/handle anymore
-        #[cfg(unix)]
-        if state.pid.is_none() {
+        // Stopping removes the state file, check its presence rather than reading it every second
+        if !State::state_file_path().exists() {
             std::process::exit(0);
         }
 
-        #[cfg(windows)]
-        if state.handle.is_none() {
-            std::process::exit(0);
-        }
-        info!(
-            "Connection status updated: success: {}, network activity: {}",
-            state.is_success, state.last_network_activity
+        let connection_status = get_connection();
+        let status = (
+            connection_status.is_success,
+            connection_status.last_network_activity,
+            connection_status.backend_error_code,
         );
 
-        sleep(Duration::from_secs(5));
+        // Only write the state on change, the waiters are notified of each write
+        if last_status.as_ref() != Some(&status) {
+            // Stop clears the state, exit rather than writing it again
+            let running = State::update(|state| {
+                state.is_success = status.0;
+                state.last_network_activity = status.1.clone();
+                state.backend_error_code = status.2.clone();
+            });
+            if !running {
+                std::process::exit(0);
+            }
+            info!(
+                "Connection status updated: success: {}, network activity: {}",
+                status.0, status.1
+            );
+            last_status = Some(status);
+        }
+
+        // Reading the connection status is cheap, poll it often to report changes quickly
+        sleep(Duration::from_secs(1));
     }
 }
 
-fn pid_exists(pid: u32) -> bool {
+pub fn process_start_time(pid: u32) -> u64 {
+    let mut system = System::new();
+    system.refresh_process(Pid::from_u32(pid));
+    match system.process(Pid::from_u32(pid)) {
+        Some(process) => process.start_time(),
+        None => 0,
+    }
+}
+
+// Check the process is still ours, a bare PID check is fooled by PID reuse
+pub fn pid_exists(pid: u32, start_time: u64) -> bool {
     let mut system = System::new_all();
     system.refresh_all();
-    system.process(Pid::from_u32(pid)).is_some()
+    match system.process(Pid::from_u32(pid)) {
+        Some(process) => {
+            let exe_name = std::env::current_exe()
+                .ok()
+                .and_then(|exe| {
+                    exe.file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                })
+                .unwrap_or_default();
+            // Process names can be truncated (15 chars on Linux)
+            let same_name = exe_name.is_empty() || exe_name.starts_with(process.name());
+            let same_start = start_time == 0 || process.start_time() == start_time;
+            same_name && same_start
+        }
+        None => false,
+    }
+}
+
+fn instance_running(state: &State) -> bool {
+    match state.pid {
+        Some(pid) if pid_exists(pid, state.start_time) => true,
+        // The state may have been cleared while the process is still exiting
+        _ => InstanceLock::is_held(),
+    }
+}
+
+fn wait_for_instance_exit(timeout: u64) -> bool {
+    let mut elapsed = 0;
+    while InstanceLock::is_held() {
+        if elapsed >= timeout {
+            return false;
+        }
+        sleep(Duration::from_secs(1));
+        elapsed += 1;
+    }
+    true
 }
 
 pub fn show_background_process_status() {
     let state = State::load();
     if let Some(pid) = state.pid {
-        if pid_exists(pid) {
+        if pid_exists(pid, state.start_time) {
             println!("Background process running ({})", pid);
+            if state.max_duration > 0 {
+                println!("  - Max duration: {}s", state.max_duration);
+            }
+            if let Some(parent_pid) = state.parent_pid {
+                println!("  - Watched process: {}", parent_pid);
+            }
+            if !state.gateway_outcome.is_empty() {
+                println!("  - LAN scan: {}", state.gateway_outcome);
+            }
+            if state.lan_scanning {
+                println!("  - LAN scan profile: {}", state.scan.profile.name());
+            }
+            if state.egress_interval > 0 {
+                println!("  - Egress monitor: every {}s", state.egress_interval);
+            }
+            if state.memory_watch {
+                let events = read_memory_access_events();
+                println!("  - Memory access watch: {} detection(s)", events.len());
+                for event in events.iter() {
+                    println!(
+                        "    - {} Active threat: {}",
+                        event.timestamp.to_rfc3339(),
+                        describe_memory_access(&event.access)
+                    );
+                }
+            }
             // Read connection status
             let connection_status = get_connection();
             println!("Connection status:");
@@ -162,13 +490,41 @@ pub fn show_background_process_status() {
     }
 }
 
-pub fn start_background_process(
-    user: String,
-    domain: String,
-    pin: String,
-    device_id: String,
-    lan_scanning: bool,
-) {
+pub fn start_background_process(params: BackgroundParams, replace: bool, wait: Option<u64>) {
+    // Validate the network selection before launching
+    if let Err(e) = params.network.to_network() {
+        eprintln!("{}", e);
+        // Exit with an error code
+        std::process::exit(1);
+    }
+
+    // Refuse to start a second instance unless asked to replace it
+    let state = State::load();
+    if instance_running(&state) {
+        let pid = state
+            .pid
+            .map(|pid| pid.to_string())
+            .unwrap_or("unknown".to_string());
+        if !replace {
+            eprintln!(
+                "Background process already running ({}), stop it first or use --replace",
+                pid
+            );
+            // Exit with an error code
+            std::process::exit(1);
+        }
+        println!("Replacing running background process ({})", pid);
+        stop_background_process();
+        if !wait_for_instance_exit(60) {
+            eprintln!("Timeout waiting for background process ({}) to exit", pid);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    } else if state.pid.is_some() {
+        // Stale state left by a dead process
+        State::clear();
+    }
+
     // Show core version
     handle_get_core_version();
 
@@ -179,6 +535,8 @@ pub fn start_background_process(
 
     #[cfg(unix)]
     {
+        // Forget the process of a previous start, the new one is recorded once spawned
+        State::clear_launched_pid();
         let daemonize = Daemonize::new()
             .pid_file("/tmp/edamame.pid")
             .chown_pid_file(true)
@@ -187,22 +545,34 @@ pub fn start_background_process(
                 move || -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                     let child = ProcessCommand::new(std::env::current_exe().unwrap())
                         .arg("background-process")
-                        .arg(&user)
-                        .arg(&domain)
-                        .arg(&pin)
-                        .arg(&device_id)
-                        .arg(&lan_scanning.to_string())
+                        .args(params.to_args())
                         .spawn()
                         .expect("Failed to start background process");
 
                     println!("Background process ({}) launched", child.id());
+                    // Lets the waiters fail at once if it exits before writing the state
+                    State::save_launched_pid(child.id());
                     Ok(())
                 },
             );
 
-        match daemonize.start() {
-            Ok(_) => println!("Successfully daemonized"),
-            Err(e) => eprintln!("Error daemonizing: {}", e),
+        // Keep the parent alive so that it can wait for the connection
+        match daemonize.execute() {
+            Outcome::Parent(Ok(_)) => {}
+            Outcome::Parent(Err(e)) => {
+                eprintln!("Error daemonizing: {}", e);
+                // Exit with an error code
+                std::process::exit(1);
+            }
+            Outcome::Child(Ok(_)) => {
+                println!("Successfully daemonized");
+                std::process::exit(0);
+            }
+            Outcome::Child(Err(e)) => {
+                eprintln!("Error daemonizing: {}", e);
+                // Exit with an error code
+                std::process::exit(1);
+            }
         }
     }
 
@@ -213,15 +583,7 @@ pub fn start_background_process(
             .display()
             .to_string();
         // Format the command line string, quoting the executable path if it contains spaces
-        let cmd = format!(
-            "{} background-process {} {} {} {} {}",
-            exe,
-            user,
-            domain,
-            pin,
-            device_id,
-            lan_scanning.to_string()
-        );
+        let cmd = format!("{} background-process {}", exe, params.to_args().join(" "));
 
         let creation_flags = CREATE_UNICODE_ENVIRONMENT | DETACHED_PROCESS;
         let mut process_information = PROCESS_INFORMATION::default();
@@ -276,14 +638,10 @@ pub fn start_background_process(
                 //println!("exitcode: {}", exit_code);
 
                 // Save state within the parent for Windows
-                let state = State {
-                    pid: Some(process_information.dwProcessId),
-                    handle: Some(process_information.hProcess.0 as u64),
-                    is_success: false,
-                    connected_domain: domain,
-                    connected_user: user,
-                    last_network_activity: "".to_string(),
-                };
+                let state = params.to_state(
+                    process_information.dwProcessId,
+                    Some(process_information.hProcess.0 as u64),
+                );
                 state.save();
 
                 unsafe {
@@ -297,13 +655,81 @@ pub fn start_background_process(
             }
         }
     }
+
+    if let Some(timeout) = wait {
+        let phases = [WaitPhase::Daemon, WaitPhase::Connection, WaitPhase::Report];
+        let options = WaitOptions {
+            poll_interval: 1,
+            watch_launch: true,
+            ..WaitOptions::new(timeout)
+        };
+        match wait_for_phases(&phases, &options) {
+            Ok(state) => println!(
+                "Background process connected to domain {} with user {}",
+                state.connected_domain, state.connected_user
+            ),
+            Err(e) => {
+                eprintln!("{}", e);
+                // Exit with an error code
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+// Environment variable holding the PIN for a restart, when it is not given on the command line
+const PIN_VARIABLE: &str = "EDAMAME_POSTURE_PIN";
+
+pub fn restart_background_process(
+    pin: Option<&String>,
+    parent_pid: Option<u32>,
+    wait: Option<u64>,
+) {
+    let state = State::load();
+    if state.connected_user.is_empty() || state.connected_domain.is_empty() {
+        eprintln!("No saved background process parameters, use start instead");
+        // Exit with an error code
+        std::process::exit(1);
+    }
+    // The PIN is not saved with the other parameters
+    let pin = match pin
+        .cloned()
+        .or_else(|| std::env::var(PIN_VARIABLE).ok())
+        .filter(|pin| !pin.is_empty())
+    {
+        Some(pin) => pin,
+        None => {
+            eprintln!(
+                "The PIN is required, give it as an argument or set {}",
+                PIN_VARIABLE
+            );
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    };
+
+    if instance_running(&state) {
+        stop_background_process();
+        if !wait_for_instance_exit(60) {
+            eprintln!("Timeout waiting for background process to exit");
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    }
+
+    // The watched process of the previous start has usually exited by now, or its PID was reused
+    let params = BackgroundParams {
+        parent_pid,
+        ..BackgroundParams::from_state(&state, pin)
+    };
+    start_background_process(params, false, wait);
 }
 
 #[cfg(unix)]
 pub fn stop_background_process() {
     let state = State::load();
     if let Some(pid) = state.pid {
-        if pid_exists(pid) {
+        if pid_exists(pid, state.start_time) {
             println!("Stopping background process ({})", pid);
             // Don't kill, rather stop the child loop
             //let _ = ProcessCommand::new("kill").arg(pid.to_string()).status();
@@ -313,6 +739,7 @@ pub fn stop_background_process() {
             disconnect_domain();
         } else {
             eprintln!("No background process found ({})", pid);
+            State::clear();
         }
     } else {
         eprintln!("No background process is running.");
@@ -0,0 +1,470 @@
+use crate::{LanDevice, LanInventory};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// Difference between two LAN scans, devices are matched by MAC address
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum LanChange {
+    NewDevice {
+        mac: String,
+        ips: Vec<String>,
+        hostname: String,
+        vendor: String,
+    },
+    RemovedDevice {
+        mac: String,
+        ips: Vec<String>,
+        hostname: String,
+        vendor: String,
+    },
+    IpChange {
+        mac: String,
+        old_ips: Vec<String>,
+        new_ips: Vec<String>,
+    },
+    NewPort {
+        mac: String,
+        ips: Vec<String>,
+        port: u16,
+        service: String,
+    },
+    ClosedPort {
+        mac: String,
+        ips: Vec<String>,
+        port: u16,
+        service: String,
+    },
+    CriticalityChange {
+        mac: String,
+        ips: Vec<String>,
+        old: String,
+        new: String,
+    },
+}
+
+impl LanChange {
+    // Kind as used by --fail-on
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LanChange::NewDevice { .. } => "new-device",
+            LanChange::RemovedDevice { .. } => "removed-device",
+            LanChange::IpChange { .. } => "ip-change",
+            LanChange::NewPort { .. } => "new-port",
+            LanChange::ClosedPort { .. } => "closed-port",
+            LanChange::CriticalityChange { .. } => "criticality-change",
+        }
+    }
+}
+
+pub const LAN_CHANGE_KINDS: [&str; 6] = [
+    "new-device",
+    "removed-device",
+    "ip-change",
+    "new-port",
+    "closed-port",
+    "criticality-change",
+];
+
+impl fmt::Display for LanChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LanChange::NewDevice {
+                mac,
+                ips,
+                hostname,
+                vendor,
+            } => write!(
+                f,
+                "New device {} ({}) '{}' from {}",
+                mac,
+                ips.join(", "),
+                hostname,
+                vendor
+            ),
+            LanChange::RemovedDevice {
+                mac,
+                ips,
+                hostname,
+                vendor,
+            } => write!(
+                f,
+                "Device {} ({}) '{}' from {} disappeared",
+                mac,
+                ips.join(", "),
+                hostname,
+                vendor
+            ),
+            LanChange::IpChange {
+                mac,
+                old_ips,
+                new_ips,
+            } => write!(
+                f,
+                "Device {} changed IPs from {} to {}",
+                mac,
+                old_ips.join(", "),
+                new_ips.join(", ")
+            ),
+            LanChange::NewPort {
+                mac,
+                ips,
+                port,
+                service,
+            } => write!(
+                f,
+                "Device {} ({}) opened port {} ({})",
+                mac,
+                ips.join(", "),
+                port,
+                service
+            ),
+            LanChange::ClosedPort {
+                mac,
+                ips,
+                port,
+                service,
+            } => write!(
+                f,
+                "Device {} ({}) closed port {} ({})",
+                mac,
+                ips.join(", "),
+                port,
+                service
+            ),
+            LanChange::CriticalityChange { mac, ips, old, new } => write!(
+                f,
+                "Device {} ({}) criticality changed from {} to {}",
+                mac,
+                ips.join(", "),
+                old,
+                new
+            ),
+        }
+    }
+}
+
+// Devices without a MAC address (e.g. behind a router) fall back to their IP,
+// the smallest address is used so that the key does not depend on the scan order
+fn device_key(device: &LanDevice) -> String {
+    match device.macs().iter().min() {
+        Some(mac) => mac.clone(),
+        None => format!(
+            "ip:{}",
+            device.ips().iter().min().cloned().unwrap_or_default()
+        ),
+    }
+}
+
+fn sorted(mut values: Vec<String>) -> Vec<String> {
+    values.sort();
+    values
+}
+
+pub fn diff_lan_devices(old: &[LanDevice], new: &[LanDevice]) -> Vec<LanChange> {
+    let old_by_key: HashMap<String, &LanDevice> = old
+        .iter()
+        .map(|device| (device_key(device), device))
+        .collect();
+    let new_by_key: HashMap<String, &LanDevice> = new
+        .iter()
+        .map(|device| (device_key(device), device))
+        .collect();
+    let mut changes = Vec::new();
+
+    for device in new.iter() {
+        let key = device_key(device);
+        let previous = match old_by_key.get(&key) {
+            Some(previous) => previous,
+            None => {
+                changes.push(LanChange::NewDevice {
+                    mac: key,
+                    ips: device.ips(),
+                    hostname: device.hostname.clone(),
+                    vendor: device.device_vendor.clone(),
+                });
+                continue;
+            }
+        };
+
+        if sorted(previous.ips()) != sorted(device.ips()) {
+            changes.push(LanChange::IpChange {
+                mac: key.clone(),
+                old_ips: previous.ips(),
+                new_ips: device.ips(),
+            });
+        }
+
+        for port in device.open_ports.iter() {
+            if !previous.open_ports.iter().any(|p| p.port == port.port) {
+                changes.push(LanChange::NewPort {
+                    mac: key.clone(),
+                    ips: device.ips(),
+                    port: port.port,
+                    service: port.service.clone(),
+                });
+            }
+        }
+        for port in previous.open_ports.iter() {
+            if !device.open_ports.iter().any(|p| p.port == port.port) {
+                changes.push(LanChange::ClosedPort {
+                    mac: key.clone(),
+                    ips: device.ips(),
+                    port: port.port,
+                    service: port.service.clone(),
+                });
+            }
+        }
+
+        if previous.criticality != device.criticality {
+            changes.push(LanChange::CriticalityChange {
+                mac: key.clone(),
+                ips: device.ips(),
+                old: previous.criticality.clone(),
+                new: device.criticality.clone(),
+            });
+        }
+    }
+
+    for device in old.iter() {
+        let key = device_key(device);
+        if !new_by_key.contains_key(&key) {
+            changes.push(LanChange::RemovedDevice {
+                mac: key,
+                ips: device.ips(),
+                hostname: device.hostname.clone(),
+                vendor: device.device_vendor.clone(),
+            });
+        }
+    }
+
+    changes
+}
+
+pub fn load_lan_baseline(path: &str) -> Result<LanInventory, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Unable to read baseline {}: {}", path, e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("Unable to parse baseline {}: {}", path, e))
+}
+
+pub fn save_lan_baseline(inventory: &LanInventory, path: &str) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(inventory)
+        .map_err(|e| format!("Unable to serialize baseline: {}", e))?;
+    fs::write(path, contents).map_err(|e| format!("Unable to write baseline {}: {}", path, e))
+}
+
+// Compare the scan with the baseline, create the baseline if it does not exist yet
+// Returns the changes that match the fail_on kinds
+pub fn handle_lan_baseline(
+    inventory: &LanInventory,
+    path: &str,
+    update: bool,
+    fail_on: &[String],
+) -> Vec<LanChange> {
+    // An incomplete scan would report every device as removed
+    if inventory.last_scan.is_empty() {
+        eprintln!("LAN scan not completed, baseline {} not compared", path);
+        // Exit with an error code
+        std::process::exit(1);
+    }
+
+    if !Path::new(path).exists() {
+        match save_lan_baseline(inventory, path) {
+            Ok(_) => println!(
+                "Baseline {} created with {} devices",
+                path,
+                inventory.devices.len()
+            ),
+            Err(e) => eprintln!("{}", e),
+        }
+        return vec![];
+    }
+
+    let baseline = match load_lan_baseline(path) {
+        Ok(baseline) => baseline,
+        Err(e) => {
+            eprintln!("{}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    };
+
+    let changes = diff_lan_devices(&baseline.devices, &inventory.devices);
+    println!(
+        "LAN drift against baseline {} (scanned at {}):",
+        path, baseline.last_scan
+    );
+    for change in changes.iter() {
+        println!("  - {}", change);
+    }
+    for kind in LAN_CHANGE_KINDS.iter() {
+        let count = changes.iter().filter(|c| c.kind() == *kind).count();
+        if count > 0 {
+            println!("  - {}: {}", kind, count);
+        }
+    }
+    if changes.is_empty() {
+        println!("  - No change");
+    }
+    println!();
+
+    if update {
+        match save_lan_baseline(inventory, path) {
+            Ok(_) => println!("Baseline {} updated", path),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    changes
+        .into_iter()
+        .filter(|change| fail_on.iter().any(|kind| kind == change.kind()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LanPort;
+
+    fn device(macs: &[&str], ips: &[&str]) -> LanDevice {
+        LanDevice {
+            hostname: String::new(),
+            custom_name: String::new(),
+            ip_address: ips.first().map(|ip| ip.to_string()).unwrap_or_default(),
+            ip_addresses: ips.iter().map(|ip| ip.to_string()).collect(),
+            mac_address: macs.first().map(|mac| mac.to_string()).unwrap_or_default(),
+            mac_addresses: macs.iter().map(|mac| mac.to_string()).collect(),
+            mdns_services: Vec::new(),
+            os_name: String::new(),
+            os_version: String::new(),
+            device_vendor: String::new(),
+            device_type: String::new(),
+            criticality: "low".to_string(),
+            has_edamame: false,
+            edamame_version: String::new(),
+            open_ports: Vec::new(),
+            dismissed_ports: Vec::new(),
+            vulnerabilities: Vec::new(),
+            active: true,
+            added: false,
+            activated: false,
+            deactivated: false,
+            deleted: false,
+            no_icmp: false,
+            non_std_ports: false,
+            last_time_seen: String::new(),
+        }
+    }
+
+    fn kinds(changes: &[LanChange]) -> Vec<(&'static str, String)> {
+        changes
+            .iter()
+            .map(|change| {
+                let key = match change {
+                    LanChange::NewDevice { mac, .. }
+                    | LanChange::RemovedDevice { mac, .. }
+                    | LanChange::IpChange { mac, .. }
+                    | LanChange::NewPort { mac, .. }
+                    | LanChange::ClosedPort { mac, .. }
+                    | LanChange::CriticalityChange { mac, .. } => mac.clone(),
+                };
+                (change.kind(), key)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff() {
+        let cases = vec![
+            // Same device, scanned again
+            (
+                device(&["aa:00:00:00:00:01"], &["10.0.0.1"]),
+                device(&["aa:00:00:00:00:01"], &["10.0.0.1"]),
+                vec![],
+            ),
+            // The MAC is matched regardless of case and of the order of the addresses
+            (
+                device(&["aa:00:00:00:00:02", "aa:00:00:00:00:01"], &["10.0.0.1"]),
+                device(&["AA:00:00:00:00:01", "aa:00:00:00:00:02"], &["10.0.0.1"]),
+                vec![],
+            ),
+            // Same MAC, new IP
+            (
+                device(&["aa:00:00:00:00:01"], &["10.0.0.1"]),
+                device(&["aa:00:00:00:00:01"], &["10.0.0.2"]),
+                vec![("ip-change", "aa:00:00:00:00:01")],
+            ),
+            // Same IP, new MAC: another device
+            (
+                device(&["aa:00:00:00:00:01"], &["10.0.0.1"]),
+                device(&["aa:00:00:00:00:02"], &["10.0.0.1"]),
+                vec![
+                    ("new-device", "aa:00:00:00:00:02"),
+                    ("removed-device", "aa:00:00:00:00:01"),
+                ],
+            ),
+            // Without a MAC, the smallest IP is the key
+            (
+                device(&[], &["10.0.0.2", "10.0.0.1"]),
+                device(&[], &["10.0.0.1", "10.0.0.2"]),
+                vec![],
+            ),
+            (
+                device(&[], &["10.0.0.1"]),
+                device(&[], &["10.0.0.2"]),
+                vec![
+                    ("new-device", "ip:10.0.0.2"),
+                    ("removed-device", "ip:10.0.0.1"),
+                ],
+            ),
+        ];
+        for (old, new, expected) in cases {
+            let changes = diff_lan_devices(std::slice::from_ref(&old), std::slice::from_ref(&new));
+            let expected: Vec<(&str, String)> = expected
+                .iter()
+                .map(|(kind, key)| (*kind, key.to_string()))
+                .collect();
+            assert_eq!(kinds(&changes), expected, "{:?} -> {:?}", old, new);
+        }
+    }
+
+    #[test]
+    fn diff_ports_and_criticality() {
+        let port = |port: u16| LanPort {
+            port,
+            protocol: "tcp".to_string(),
+            service: String::new(),
+            banner: String::new(),
+            vulnerabilities: Vec::new(),
+        };
+        let mut old = device(&["aa:00:00:00:00:01"], &["10.0.0.1"]);
+        old.open_ports = vec![port(22), port(80)];
+        let mut new = old.clone();
+        new.open_ports = vec![port(80), port(443)];
+        new.criticality = "high".to_string();
+
+        let changes = diff_lan_devices(&[old], &[new]);
+        assert!(changes.contains(&LanChange::NewPort {
+            mac: "aa:00:00:00:00:01".to_string(),
+            ips: vec!["10.0.0.1".to_string()],
+            port: 443,
+            service: String::new(),
+        }));
+        assert!(changes.contains(&LanChange::ClosedPort {
+            mac: "aa:00:00:00:00:01".to_string(),
+            ips: vec!["10.0.0.1".to_string()],
+            port: 22,
+            service: String::new(),
+        }));
+        assert!(changes.contains(&LanChange::CriticalityChange {
+            mac: "aa:00:00:00:00:01".to_string(),
+            ips: vec!["10.0.0.1".to_string()],
+            old: "low".to_string(),
+            new: "high".to_string(),
+        }));
+        assert_eq!(changes.len(), 3);
+    }
+}
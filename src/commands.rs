@@ -1,79 +1,67 @@
-use crate::{display_logs, stop_background_process, State};
+use crate::{
+    apply_device_database, collect_system_report, detect_link, display_logs, print_lan_inventory,
+    print_system_report, stop_background_process, wait_for_phases, LanDisplayOptions, LanInventory,
+    NetworkSelection, WaitOptions, WaitPhase,
+};
 use edamame_core::api::api_core::{
     connect_domain, get_core_info, get_core_version, get_device_info, request_pin, set_credentials,
 };
-use edamame_core::api::api_lanscan::{get_lan_devices, set_network, LANScanAPINetwork};
+use edamame_core::api::api_lanscan::{get_lan_devices, set_network, LANScanAPI};
 use edamame_core::api::api_score::{compute_score, get_score};
 use edamame_core::api::api_score_threats::{get_threats_url, remediate};
 use indicatif::{ProgressBar, ProgressStyle};
 use std::thread::sleep;
-use std::time::Duration;
-use sysinfo::{Disks, Networks, System};
+use std::time::{Duration, Instant};
 
-pub fn handle_wait_for_connection(timeout: u64) {
+pub fn handle_wait_for_connection(options: &WaitOptions) {
     handle_get_device_info();
 
-    handle_get_system_info();
-
-    println!("Waiting for score computation and reporting to complete...");
-    let mut timeout = timeout;
-    // Read the state and wait until a network activity is detected and the connection is successful
-    let mut state = State::load();
-    while !(state.is_success && state.last_network_activity != "") && timeout > 0 {
-        sleep(Duration::from_secs(5));
-        timeout = timeout - 5;
-        state = State::load();
-        println!("Waiting for score computation and reporting to complete... (success: {}, network activity: {})", state.is_success, state.last_network_activity);
+    handle_get_system_info(false, false);
+
+    // Wait for the background process to go through its startup phases
+    let phases = [
+        WaitPhase::Daemon,
+        WaitPhase::LanScan,
+        WaitPhase::Connection,
+        WaitPhase::Report,
+    ];
+    let state = match wait_for_phases(&phases, options) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{}, killing process...", e);
+            stop_background_process();
+
+            display_logs();
+
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    };
+
+    // Compute and display the score
+    compute_score();
+    handle_score(true);
+
+    // Initialize network with the selection of the background process
+    match state.network.to_network() {
+        Ok(network) => set_network(network),
+        Err(e) => {
+            eprintln!("{}, using autodetection", e);
+            set_network(NetworkSelection::autodetect());
+        }
     }
 
-    if timeout <= 0 {
-        eprintln!(
-            "Timeout waiting for background process to connect to domain, killing process..."
-        );
-        stop_background_process();
+    // Consent has been granted and scan has completed by the child
 
-        display_logs();
+    // Print the lanscan results, don't wait
+    handle_lanscan(false, None, &LanDisplayOptions::default());
 
-        // Exit with an error code
-        std::process::exit(1);
-    } else {
-        // Compute and display the score
-        compute_score();
-        handle_score(true);
-
-        // Initialize network to autodetect
-        set_network(LANScanAPINetwork {
-            interfaces: vec![],
-            scanned_interfaces: vec![],
-            is_ethernet: true,
-            is_wifi: false,
-            is_vpn: false,
-            is_tethering: false,
-            is_mobile: false,
-            wifi_bssid: "".to_string(),
-            wifi_ip: "".to_string(),
-            wifi_submask: "".to_string(),
-            wifi_gateway: "".to_string(),
-            wifi_broadcast: "".to_string(),
-            wifi_name: "".to_string(),
-            wifi_ipv6: "".to_string(),
-        });
-
-        // Consent has been granted and scan has completed by the child
-
-        // Print the lanscan results, don't wait
-        handle_lanscan(false);
-
-        display_logs();
-
-        println!(
-            "Connection successful with domain {} and user {} (success: {}, network activity: {})",
-            state.connected_domain,
-            state.connected_user,
-            state.is_success,
-            state.last_network_activity
-        );
-    }
+    display_logs();
+
+    println!(
+        "Connection successful with domain {} and user {} (success: {}, network activity: {})",
+        state.connected_domain, state.connected_user, state.is_success, state.last_network_activity
+    );
 }
 
 pub fn handle_get_core_info() {
@@ -92,6 +80,18 @@ pub fn handle_get_device_info() {
     println!("  - IPv4: {}", device_info.ip4);
     println!("  - IPv6: {}", device_info.ip6);
     println!("  - MAC: {}", device_info.mac);
+
+    let link = detect_link(None);
+    println!("Network:");
+    println!("  - Interface: {}", link.interface);
+    println!("  - Type: {}", link.kind());
+    if link.is_wifi {
+        println!("  - SSID: {}", link.ssid);
+        println!("  - BSSID: {}", link.bssid);
+    }
+    println!("  - IPv4: {}", link.ip);
+    println!("  - Netmask: {}", link.submask);
+    println!("  - Gateway: {}", link.gateway);
 }
 
 pub fn handle_get_threats_info() {
@@ -118,7 +118,8 @@ pub fn handle_get_core_version() {
     println!("Core version: {}", version);
 }
 
-pub fn handle_lanscan(wait_for_completion: bool) {
+// Wait for the core LAN scan to complete, up to max_wait seconds if set
+pub fn wait_for_lanscan(wait_for_completion: bool, max_wait: Option<u64>) -> LANScanAPI {
     let mut devices = get_lan_devices(false, false, false);
     // Interfaces are in the form (ip, subnet, name)
     let interfaces = devices
@@ -140,56 +141,44 @@ pub fn handle_lanscan(wait_for_completion: bool) {
         .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} ({eta})")
         .progress_chars("#>-"));
 
-    // Wait completion of the scan
+    // Wait completion of the scan, up to max_wait seconds if set
+    let deadline = max_wait.map(|max_wait| Instant::now() + Duration::from_secs(max_wait));
     devices = get_lan_devices(false, false, false);
     if wait_for_completion {
         while devices.scan_in_progress {
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                println!(
+                    "Stopped waiting for the LAN scan after {}s, results may be partial",
+                    max_wait.unwrap_or(0)
+                );
+                break;
+            }
             pb.set_position(devices.scan_progress_percent as u64);
             sleep(Duration::from_secs(5));
             devices = get_lan_devices(false, false, false);
         }
     }
 
+    devices
+}
+
+pub fn handle_lanscan(
+    wait_for_completion: bool,
+    max_wait: Option<u64>,
+    display: &LanDisplayOptions,
+) -> LANScanAPI {
+    let devices = wait_for_lanscan(wait_for_completion, max_wait);
+
     if devices.last_scan == "" {
         println!("LAN scan not completed");
-        return;
     } else {
-        println!("LAN scan completed at: {}", devices.last_scan);
-    }
-
-    for device in devices.devices.iter() {
-        println!("  - '{}'", device.hostname);
-        println!("    - Type: {}", device.device_type);
-        println!("    - Vendor: {}", device.device_vendor);
-        println!("    - IPs: {}", device.ip_addresses.join(", "));
-        println!("    - MACs: {}", device.mac_addresses.join(", "));
-        println!("    - Has EDAMAME: {}", device.has_edamame);
-        println!("    - Criticality: {}", device.criticality);
-        println!(
-            "    - Open ports: {}",
-            device
-                .open_ports
-                .iter()
-                .map(|port| port.port.to_string())
-                .collect::<Vec<String>>()
-                .join(", ")
+        print_lan_inventory(
+            &apply_device_database(LanInventory::from(&devices)),
+            display,
         );
     }
-    println!(
-        "Total devices: {}, {} devices have EDAMAME, {} devices are highly critical",
-        devices.devices.len(),
-        devices
-            .devices
-            .iter()
-            .filter(|device| device.has_edamame)
-            .count(),
-        devices
-            .devices
-            .iter()
-            .filter(|device| device.criticality == "High")
-            .count()
-    );
-    println!("");
+
+    devices
 }
 
 pub fn handle_score(progress_bar: bool) {
@@ -243,83 +232,15 @@ pub fn handle_score(progress_bar: bool) {
     println!("");
 }
 
-pub fn handle_get_system_info() {
-    let mut sys = System::new_all();
-    sys.refresh_all();
-    sysinfo::set_open_files_limit(0);
-
-    println!("System information:");
-    // RAM and swap information
-    println!("  - Total memory: {} bytes", sys.total_memory());
-    println!("  - Used memory : {} bytes", sys.used_memory());
-    println!("  - Total swap  : {} bytes", sys.total_swap());
-    println!("  - Used swap   : {} bytes", sys.used_swap());
-
-    // Display system information
-    println!("  - System name:             {:?}", System::name());
-    println!(
-        "  - System kernel version:   {:?}",
-        System::kernel_version()
-    );
-    println!("  - System OS version:       {:?}", System::os_version());
-    println!("  - System host name:        {:?}", System::host_name());
-
-    // Number of CPUs
-    println!("  - NB CPUs: {}", sys.cpus().len());
-
-    // We display all disks' information
-    println!("  - Disks:");
-    let disks = Disks::new_with_refreshed_list();
-    for disk in &disks {
-        println!("    - {disk:?}");
-    }
-
-    // Network interfaces name
-    let networks = Networks::new_with_refreshed_list();
-    println!("  - Networks:");
-    for (interface_name, _data) in &networks {
-        println!("    - {interface_name}",);
-    }
-
-    // Platform-specific information
-    #[cfg(target_os = "macos")]
-    {
-        use std::process::Command;
-
-        let output = Command::new("system_profiler")
-            .arg("SPHardwareDataType")
-            .output()
-            .expect("Failed to execute command");
-
-        println!("macOS specific information:");
-        println!("  - System profiler hardware data:");
-        println!("{}", String::from_utf8_lossy(&output.stdout));
-    }
-
-    #[cfg(target_os = "linux")]
-    {
-        use std::fs;
-
-        let cpuinfo = fs::read_to_string("/proc/cpuinfo").expect("Failed to read /proc/cpuinfo");
-
-        println!("Linux specific information:");
-        println!("  - CPU information from /proc/cpuinfo:");
-        println!("{}", cpuinfo);
-    }
-
-    #[cfg(target_os = "windows")]
-    {
-        use std::process::Command;
-
-        let output = Command::new("powershell")
-            .arg("-Command")
-            .arg("Get-WmiObject -Class Win32_ComputerSystem | Select-Object -Property Model")
-            .output()
-            .expect("Failed to execute command");
-
-        println!("Windows specific information:");
-        println!("  - Computer system model from WMI:");
-        println!("{}", String::from_utf8_lossy(&output.stdout));
+pub fn handle_get_system_info(verbose: bool, json: bool) {
+    let report = collect_system_report(verbose);
+    if json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Unable to serialize the system report: {}", e),
+        }
+    } else {
+        print_system_report(&report);
     }
 }
 
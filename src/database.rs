@@ -0,0 +1,378 @@
+use crate::{LanInventory, SERVICE_NAMES};
+use chrono::Utc;
+use edamame_core::api::api_core::get_core_version;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// OUI database imported from disk, takes precedence over the one bundled in the core
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct OuiDatabase {
+    pub imported_at: String,
+    pub source: String,
+    // Upper case hex prefix (6, 7 or 9 digits for MA-L, MA-M and MA-S blocks) to vendor
+    pub entries: BTreeMap<String, String>,
+}
+
+// Friendly name, type and vendor of a known device, by MAC address
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DeviceOverride {
+    pub mac: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, rename = "type")]
+    pub device_type: Option<String>,
+    #[serde(default)]
+    pub vendor: Option<String>,
+}
+
+// Device overrides file, in YAML
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct DeviceOverrides {
+    pub devices: Vec<DeviceOverride>,
+}
+
+fn oui_file_path() -> PathBuf {
+    dirs::home_dir()
+        .expect("Unable to find home directory")
+        .join(".edamame_posture_oui.json")
+}
+
+pub fn overrides_file_path() -> PathBuf {
+    dirs::home_dir()
+        .expect("Unable to find home directory")
+        .join(".edamame_posture_overrides.yaml")
+}
+
+fn hex_digits(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+// Minimal CSV line split, with quoted fields
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+// Accepts the IEEE oui.txt and CSV registries (MA-L, MA-M, MA-S) and the Wireshark manuf file
+pub fn parse_oui(contents: &str) -> BTreeMap<String, String> {
+    let mut entries = BTreeMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // IEEE oui.txt: "00-00-0C   (hex)		Cisco Systems, Inc"
+        if let Some((prefix, vendor)) = line.split_once("(hex)") {
+            let prefix = hex_digits(prefix);
+            if prefix.len() == 6 && !vendor.trim().is_empty() {
+                entries.insert(prefix, vendor.trim().to_string());
+            }
+            continue;
+        }
+        // IEEE CSV: "MA-L,00000C,Cisco Systems, Inc,<address>"
+        if line.starts_with("MA-") {
+            let fields = csv_fields(line);
+            if fields.len() >= 3 {
+                let prefix = hex_digits(&fields[1]);
+                if matches!(prefix.len(), 6 | 7 | 9) && !fields[2].trim().is_empty() {
+                    entries.insert(prefix, fields[2].trim().to_string());
+                }
+            }
+            continue;
+        }
+        // Wireshark manuf: "00:00:0C<tab>Cisco<tab>Cisco Systems, Inc", optionally with a /28 or /36 mask
+        let fields: Vec<&str> = line.split('\t').map(|field| field.trim()).collect();
+        if fields.len() < 2 {
+            continue;
+        }
+        let (address, bits) = match fields[0].split_once('/') {
+            Some((address, bits)) => (address, bits.parse::<usize>().unwrap_or(24)),
+            None => (fields[0], 24),
+        };
+        let digits = hex_digits(address);
+        let length = bits / 4;
+        if !matches!(length, 6 | 7 | 9) || digits.len() < length {
+            continue;
+        }
+        let vendor = fields
+            .get(2)
+            .filter(|v| !v.is_empty())
+            .unwrap_or(&fields[1]);
+        entries.insert(digits[..length].to_string(), vendor.to_string());
+    }
+    entries
+}
+
+pub fn load_oui_database() -> Option<OuiDatabase> {
+    let contents = fs::read_to_string(oui_file_path()).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+pub fn import_oui_database(path: &str) -> Result<OuiDatabase, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Unable to read OUI file {}: {}", path, e))?;
+    let entries = parse_oui(&contents);
+    if entries.is_empty() {
+        return Err(format!("No OUI entry found in {}", path));
+    }
+    let database = OuiDatabase {
+        imported_at: Utc::now().to_rfc3339(),
+        source: fs::canonicalize(path)
+            .map(|path| path.display().to_string())
+            .unwrap_or(path.to_string()),
+        entries,
+    };
+    let contents = serde_json::to_string(&database)
+        .map_err(|e| format!("Unable to serialize OUI database: {}", e))?;
+    fs::write(oui_file_path(), contents)
+        .map_err(|e| format!("Unable to write OUI database: {}", e))?;
+    Ok(database)
+}
+
+impl OuiDatabase {
+    // Longest prefix match
+    pub fn vendor(&self, mac: &str) -> Option<&String> {
+        let digits = hex_digits(mac);
+        if digits.len() != 12 {
+            return None;
+        }
+        [9, 7, 6]
+            .iter()
+            .find_map(|length| self.entries.get(&digits[..*length]))
+    }
+}
+
+pub fn load_device_overrides(path: &Path) -> Result<DeviceOverrides, String> {
+    if !path.exists() {
+        return Ok(DeviceOverrides::default());
+    }
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read overrides {}: {}", path.display(), e))?;
+    serde_yaml::from_str(&contents)
+        .map_err(|e| format!("Unable to parse overrides {}: {}", path.display(), e))
+}
+
+// Apply the imported OUI database and the local overrides to the scanned devices
+pub fn apply_device_database(mut inventory: LanInventory) -> LanInventory {
+    let oui = load_oui_database();
+    let overrides = match load_device_overrides(&overrides_file_path()) {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("{}", e);
+            DeviceOverrides::default()
+        }
+    };
+    for device in inventory.devices.iter_mut() {
+        let macs = device.macs();
+        if let Some(vendor) = oui
+            .as_ref()
+            .and_then(|oui| macs.iter().find_map(|mac| oui.vendor(mac)))
+        {
+            device.device_vendor = vendor.clone();
+        }
+        let device_override = overrides.devices.iter().find(|device_override| {
+            macs.iter()
+                .any(|mac| hex_digits(mac) == hex_digits(&device_override.mac))
+        });
+        if let Some(device_override) = device_override {
+            if let Some(name) = &device_override.name {
+                device.custom_name = name.clone();
+            }
+            if let Some(device_type) = &device_override.device_type {
+                device.device_type = device_type.clone();
+            }
+            if let Some(vendor) = &device_override.vendor {
+                device.device_vendor = vendor.clone();
+            }
+        }
+    }
+    inventory
+}
+
+pub fn handle_db_status() {
+    println!("Bundled databases:");
+    println!("  - Core version: {}", get_core_version());
+    // The core API has no call returning the versions of its databases
+    println!("  - OUI database version: not exposed by the core, it ships with the core version");
+    println!(
+        "  - Port/service database version: not exposed by the core, it ships with the core version"
+    );
+    println!(
+        "  - Port/service names of the light scan profile: {} entries",
+        SERVICE_NAMES.len()
+    );
+    println!();
+
+    println!("Imported OUI database:");
+    println!("  - Path: {}", oui_file_path().display());
+    match load_oui_database() {
+        Some(database) => {
+            println!("  - Imported at: {}", database.imported_at);
+            println!("  - Source: {}", database.source);
+            println!("  - Entries: {}", database.entries.len());
+            println!("  - Only changes the displayed vendors, the core still types devices with its own database");
+        }
+        None => println!("  - Not imported, the core database is used"),
+    }
+    println!();
+
+    let path = overrides_file_path();
+    println!("Device overrides:");
+    println!("  - Path: {}", path.display());
+    match load_device_overrides(&path) {
+        Ok(_) if !path.exists() => println!("  - Not found"),
+        Ok(overrides) => {
+            println!("  - Entries: {}", overrides.devices.len());
+            for device_override in overrides.devices.iter() {
+                println!(
+                    "    - {}: name: {}, type: {}, vendor: {}",
+                    device_override.mac.to_lowercase(),
+                    device_override.name.as_deref().unwrap_or("-"),
+                    device_override.device_type.as_deref().unwrap_or("-"),
+                    device_override.vendor.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        Err(e) => println!("  - {}", e),
+    }
+    println!();
+}
+
+pub fn handle_db_import_oui(path: &str) {
+    match import_oui_database(path) {
+        Ok(database) => println!(
+            "Imported {} OUI entries from {}",
+            database.entries.len(),
+            database.source
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    }
+}
+
+// Go back to the core database
+pub fn handle_db_remove_oui() {
+    match fs::remove_file(oui_file_path()) {
+        Ok(_) => println!("Imported OUI database removed, the core database is used"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("No imported OUI database")
+        }
+        Err(e) => {
+            eprintln!("Unable to remove the imported OUI database: {}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(prefix, vendor)| (prefix.to_string(), vendor.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn ieee_txt() {
+        let contents = "OUI/MA-L                                                    Organization\n\
+            company_id                                                  Organization\n\
+                                                                        Address\n\
+            \n\
+            00-00-0C   (hex)\t\tCisco Systems, Inc\n\
+            00000C     (base 16)\t\tCisco Systems, Inc\n\
+            \t\t\t\t170 WEST TASMAN DRIVE\n";
+        assert_eq!(
+            parse_oui(contents),
+            entries(&[("00000C", "Cisco Systems, Inc")])
+        );
+    }
+
+    #[test]
+    fn ieee_csv() {
+        let contents = "Registry,Assignment,Organization Name,Organization Address\n\
+            MA-L,00000C,\"Cisco Systems, Inc\",170 WEST TASMAN DRIVE SAN JOSE CA US 95134\n\
+            MA-M,70B3D51,\"Vendor \"\"Quoted\"\" Ltd\",Somewhere\n\
+            MA-S,70B3D5F2A,Small Vendor,Somewhere\n\
+            MA-L,BADPREFIX,Broken,Somewhere\n";
+        assert_eq!(
+            parse_oui(contents),
+            entries(&[
+                ("00000C", "Cisco Systems, Inc"),
+                ("70B3D51", "Vendor \"Quoted\" Ltd"),
+                ("70B3D5F2A", "Small Vendor"),
+            ])
+        );
+    }
+
+    #[test]
+    fn wireshark_manuf() {
+        let contents = "# Wireshark manuf\n\
+            00:00:0C\tCisco\tCisco Systems, Inc\n\
+            00:00:0D\tFibronic\n\
+            70:B3:D5:10:00:00/28\tVendorM\tVendor M\n\
+            70:B3:D5:F2:A0:00/36\tVendorS\tVendor S\n\
+            70:B3:D5:F2:A0:00/40\tBad\tBad mask\n";
+        assert_eq!(
+            parse_oui(contents),
+            entries(&[
+                ("00000C", "Cisco Systems, Inc"),
+                ("00000D", "Fibronic"),
+                ("70B3D51", "Vendor M"),
+                ("70B3D5F2A", "Vendor S"),
+            ])
+        );
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let database = OuiDatabase {
+            imported_at: String::new(),
+            source: String::new(),
+            entries: entries(&[
+                ("70B3D5", "IEEE Registration Authority"),
+                ("70B3D51", "Vendor M"),
+                ("70B3D5F2A", "Vendor S"),
+            ]),
+        };
+        let cases = [
+            ("70:b3:d5:f2:a1:23", Some("Vendor S")),
+            ("70-B3-D5-F2-B1-23", Some("IEEE Registration Authority")),
+            ("70:b3:d5:1f:ff:ff", Some("Vendor M")),
+            ("70:b3:d5:20:00:00", Some("IEEE Registration Authority")),
+            ("00:00:0c:00:00:01", None),
+            ("70:b3:d5", None),
+        ];
+        for (mac, vendor) in cases {
+            assert_eq!(database.vendor(mac).map(|v| v.as_str()), vendor, "{}", mac);
+        }
+    }
+}
@@ -0,0 +1,472 @@
+use crate::{
+    proc_net_entries, process_identity, socket_owners, TCP_ESTABLISHED, TCP_LISTEN, TCP_SYN_SENT,
+};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process::Command;
+use tracing::{error, info};
+
+// Outbound destination seen by the egress monitor
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EgressDestination {
+    // tcp or udp
+    pub protocol: String,
+    pub address: String,
+    pub port: u16,
+    // "name (executable)", empty when only seen through conntrack or owned by another user
+    pub processes: Vec<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EgressLog {
+    pub started: DateTime<Utc>,
+    pub last_sample: Option<DateTime<Utc>>,
+    // Connections tracked by the kernel, including the short-lived ones and the containers
+    pub conntrack: bool,
+    pub destinations: Vec<EgressDestination>,
+}
+
+// Allowed destinations, in YAML
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct EgressAllowlist {
+    // Resolved when the allowlist is used, not when the connections were seen:
+    // hosts with rotating addresses (CDNs) are better given as CIDRs
+    pub hosts: Vec<String>,
+    // CIDRs or single addresses
+    pub cidrs: Vec<String>,
+    // Ports allowed to any destination (e.g. 53 for DNS)
+    pub ports: Vec<u16>,
+}
+
+fn egress_file_path() -> PathBuf {
+    dirs::home_dir()
+        .expect("Unable to find home directory")
+        .join(".edamame_posture_egress.json")
+}
+
+impl EgressLog {
+    pub fn new() -> Self {
+        EgressLog {
+            started: Utc::now(),
+            last_sample: None,
+            conntrack: false,
+            destinations: vec![],
+        }
+    }
+
+    pub fn load() -> Option<Self> {
+        let contents = fs::read_to_string(egress_file_path()).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    pub fn save(&self) {
+        let path = egress_file_path();
+        // Write to a temporary file and rename it, so that readers never see a partial file
+        let tmp_path = path.with_extension("json.tmp");
+        let result = serde_json::to_string_pretty(self)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| fs::write(&tmp_path, contents).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp_path, &path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Unable to write the egress log: {}", e);
+        }
+    }
+}
+
+// Loopback, multicast and broadcast destinations are not egress
+fn is_egress_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || ip.is_broadcast())
+        }
+        IpAddr::V6(ip) => {
+            // IPv4-mapped addresses of dual-stack sockets
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_egress_address(&IpAddr::V4(ip));
+            }
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast())
+        }
+    }
+}
+
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+// Connections of the kernel conntrack table, as (protocol, source, destination, destination port)
+fn conntrack_entries() -> Option<Vec<(String, IpAddr, IpAddr, u16)>> {
+    let contents = match fs::read_to_string("/proc/net/nf_conntrack") {
+        Ok(contents) => contents,
+        Err(_) => {
+            let output = Command::new("conntrack").arg("-L").output().ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            String::from_utf8_lossy(&output.stdout).to_string()
+        }
+    };
+    Some(parse_conntrack(&contents))
+}
+
+// "ipv4 2 tcp 6 117 ESTABLISHED src=10.0.0.5 dst=140.82.112.3 sport=51234 dport=443 src=... [ASSURED]",
+// "conntrack -L" lines are the same without the leading family
+fn parse_conntrack(contents: &str) -> Vec<(String, IpAddr, IpAddr, u16)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let protocol = tokens
+                .iter()
+                .find(|token| **token == "tcp" || **token == "udp")?;
+            // The first occurrences are the original direction
+            let value = |key: &str| {
+                tokens.iter().find_map(|token| {
+                    token
+                        .strip_prefix(key)
+                        .and_then(|value| value.strip_prefix('='))
+                })
+            };
+            Some((
+                protocol.to_string(),
+                value("src")?.parse().ok()?,
+                value("dst")?.parse().ok()?,
+                value("dport")?.parse().ok()?,
+            ))
+        })
+        .collect()
+}
+
+fn local_addresses() -> HashSet<IpAddr> {
+    if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .iter()
+        .map(|interface| interface.ip())
+        .collect()
+}
+
+impl EgressLog {
+    // Add a sighting, returns true if the destination or the process is new
+    fn record(
+        &mut self,
+        protocol: &str,
+        address: IpAddr,
+        port: u16,
+        process: Option<String>,
+    ) -> bool {
+        let now = Utc::now();
+        let address = address.to_string();
+        match self.destinations.iter_mut().find(|destination| {
+            destination.protocol == protocol
+                && destination.address == address
+                && destination.port == port
+        }) {
+            Some(destination) => {
+                destination.last_seen = now;
+                match process {
+                    Some(process) if !destination.processes.contains(&process) => {
+                        destination.processes.push(process);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            None => {
+                info!(
+                    "New egress destination: {} {}:{} ({:?})",
+                    protocol, address, port, process
+                );
+                self.destinations.push(EgressDestination {
+                    protocol: protocol.to_string(),
+                    address,
+                    port,
+                    processes: process.into_iter().collect(),
+                    first_seen: now,
+                    last_seen: now,
+                });
+                true
+            }
+        }
+    }
+}
+
+// Sample the outbound connections, returns true if new destinations were recorded
+pub fn sample_egress(log: &mut EgressLog) -> bool {
+    let mut changed = false;
+    let owners = socket_owners();
+    let locals = local_addresses();
+    for protocol in ["tcp", "tcp6", "udp", "udp6"] {
+        let entries = proc_net_entries(protocol);
+        // Connections accepted by our listeners are inbound
+        let listening: HashSet<u16> = entries
+            .iter()
+            .filter(|entry| entry.state == TCP_LISTEN || entry.remote_port == 0)
+            .map(|entry| entry.local_port)
+            .collect();
+        for entry in entries.iter() {
+            if entry.state != TCP_ESTABLISHED && entry.state != TCP_SYN_SENT {
+                continue;
+            }
+            if listening.contains(&entry.local_port) {
+                continue;
+            }
+            let address = match entry.remote_address.parse::<IpAddr>() {
+                Ok(address) if is_egress_address(&address) => normalize(address),
+                _ => continue,
+            };
+            if locals.contains(&address) {
+                continue;
+            }
+            let process = owners.get(&entry.inode).map(|pid| {
+                let (name, exe) = process_identity(*pid);
+                format!("{} ({})", name, exe)
+            });
+            changed |= log.record(&protocol[..3], address, entry.remote_port, process);
+        }
+    }
+    if let Some(entries) = conntrack_entries() {
+        log.conntrack = true;
+        for (protocol, source, destination, port) in entries {
+            // Inbound connections and local traffic
+            if locals.contains(&destination)
+                || !is_egress_address(&destination)
+                || !is_egress_address(&source)
+            {
+                continue;
+            }
+            changed |= log.record(&protocol, normalize(destination), port, None);
+        }
+    }
+    log.last_sample = Some(Utc::now());
+    changed
+}
+
+pub fn load_egress_allowlist(path: &str) -> Result<EgressAllowlist, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read allowlist {}: {}", path, e))?;
+    serde_yaml::from_str(&contents)
+        .map_err(|e| format!("Unable to parse allowlist {}: {}", path, e))
+}
+
+impl EgressAllowlist {
+    // Allowed networks, with the hosts resolved now
+    pub fn resolve(&self) -> Result<Vec<IpNet>, String> {
+        let mut nets = Vec::new();
+        for cidr in self.cidrs.iter() {
+            let net = match cidr.parse::<IpNet>() {
+                Ok(net) => net.trunc(),
+                Err(_) => cidr
+                    .parse::<IpAddr>()
+                    .map(IpNet::from)
+                    .map_err(|_| format!("Invalid CIDR in allowlist: {}", cidr))?,
+            };
+            nets.push(net);
+        }
+        for host in self.hosts.iter() {
+            let addresses = (host.as_str(), 0)
+                .to_socket_addrs()
+                .map_err(|e| format!("Unable to resolve {}: {}", host, e))?;
+            nets.extend(addresses.map(|address| IpNet::from(address.ip())));
+        }
+        Ok(nets)
+    }
+}
+
+fn is_allowed(destination: &EgressDestination, nets: &[IpNet], ports: &[u16]) -> bool {
+    if ports.contains(&destination.port) {
+        return true;
+    }
+    match destination.address.parse::<IpAddr>() {
+        Ok(address) => nets.iter().any(|net| net.contains(&address)),
+        Err(_) => false,
+    }
+}
+
+// Print the destinations, returns the unexpected ones when an allowlist is given
+pub fn handle_egress_report(allowlist: Option<&String>, json: bool) -> Vec<EgressDestination> {
+    let log = match EgressLog::load() {
+        Some(log) => log,
+        None => {
+            eprintln!("No egress log found, start the background process with --egress-monitor");
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    };
+    let allowed = match allowlist.map(|path| {
+        load_egress_allowlist(path).and_then(|allowlist| {
+            allowlist
+                .resolve()
+                .map(|nets| (nets, allowlist.ports.clone()))
+        })
+    }) {
+        Some(Ok(allowed)) => Some(allowed),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    let unexpected: Vec<EgressDestination> = match &allowed {
+        Some((nets, ports)) => log
+            .destinations
+            .iter()
+            .filter(|destination| !is_allowed(destination, nets, ports))
+            .cloned()
+            .collect(),
+        None => vec![],
+    };
+
+    if json {
+        let report = serde_json::json!({
+            "started": log.started,
+            "last_sample": log.last_sample,
+            "conntrack": log.conntrack,
+            "destinations": log.destinations,
+            "unexpected": unexpected,
+        });
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Unable to serialize the egress report: {}", e),
+        }
+        return unexpected;
+    }
+
+    println!("Egress destinations:");
+    println!("  - Monitoring since: {}", log.started.to_rfc3339());
+    if let Some(last_sample) = log.last_sample {
+        println!("  - Last sample: {}", last_sample.to_rfc3339());
+    }
+    println!(
+        "  - Sources: /proc/net{}",
+        if log.conntrack { ", conntrack" } else { "" }
+    );
+    for destination in log.destinations.iter() {
+        let flag = if allowed.is_none() {
+            ""
+        } else if unexpected.contains(destination) {
+            " [unexpected]"
+        } else {
+            " [allowed]"
+        };
+        println!(
+            "  - {} {}:{}{}",
+            destination.protocol, destination.address, destination.port, flag
+        );
+        println!(
+            "    - Processes: {}",
+            if destination.processes.is_empty() {
+                "unknown".to_string()
+            } else {
+                destination.processes.join(", ")
+            }
+        );
+        println!(
+            "    - Seen: {} to {}",
+            destination.first_seen.to_rfc3339(),
+            destination.last_seen.to_rfc3339()
+        );
+    }
+    println!("Total destinations: {}", log.destinations.len());
+    if allowed.is_some() {
+        println!("Unexpected destinations: {}", unexpected.len());
+    }
+    println!();
+    unexpected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_conntrack_lines() {
+        let contents = "ipv4     2 tcp      6 117 ESTABLISHED src=10.0.0.5 dst=140.82.112.3 sport=51234 dport=443 src=140.82.112.3 dst=10.0.0.5 sport=443 dport=51234 [ASSURED] mark=0 zone=0 use=2
+ipv4     2 udp      17 29 src=10.0.0.5 dst=1.1.1.1 sport=40000 dport=53 [UNREPLIED] src=1.1.1.1 dst=10.0.0.5 sport=53 dport=40000 mark=0 zone=0 use=2
+ipv6     10 tcp      6 60 SYN_SENT src=2001:db8::5 dst=2606:4700::1111 sport=41000 dport=443 [UNREPLIED] src=2606:4700::1111 dst=2001:db8::5 sport=443 dport=41000 mark=0 zone=0 use=2
+tcp      6 431999 ESTABLISHED src=172.17.0.2 dst=151.101.0.223 sport=36000 dport=80 src=151.101.0.223 dst=192.168.1.2 sport=80 dport=36000 [ASSURED] mark=0 use=1
+ipv4     2 icmp     1 29 src=10.0.0.5 dst=8.8.8.8 type=8 code=0 id=1 src=8.8.8.8 dst=10.0.0.5 type=0 code=0 id=1 mark=0 zone=0 use=2
+ipv4     2 tcp      6 117 ESTABLISHED src=10.0.0.5 dst=not-an-ip sport=1 dport=443
+conntrack v1.4.6 (conntrack-tools): 4 flow entries have been shown.
+";
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            parse_conntrack(contents),
+            vec![
+                ("tcp".to_string(), ip("10.0.0.5"), ip("140.82.112.3"), 443),
+                ("udp".to_string(), ip("10.0.0.5"), ip("1.1.1.1"), 53),
+                (
+                    "tcp".to_string(),
+                    ip("2001:db8::5"),
+                    ip("2606:4700::1111"),
+                    443
+                ),
+                ("tcp".to_string(), ip("172.17.0.2"), ip("151.101.0.223"), 80),
+            ]
+        );
+    }
+
+    fn destination(address: &str, port: u16) -> EgressDestination {
+        EgressDestination {
+            protocol: "tcp".to_string(),
+            address: address.to_string(),
+            port,
+            processes: vec![],
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn allowlist_matching() {
+        let allowlist = EgressAllowlist {
+            hosts: vec![],
+            cidrs: vec![
+                "140.82.112.0/20".to_string(),
+                "1.1.1.1".to_string(),
+                "2606:4700::/32".to_string(),
+            ],
+            ports: vec![53],
+        };
+        let nets = allowlist.resolve().unwrap();
+        let cases = [
+            ("140.82.112.3", 443, true),
+            ("140.82.128.1", 443, false),
+            ("1.1.1.1", 443, true),
+            ("1.1.1.2", 443, false),
+            ("2606:4700::1111", 443, true),
+            ("2001:db8::1", 443, false),
+            // Allowed to any destination
+            ("8.8.8.8", 53, true),
+            ("not-an-ip", 443, false),
+        ];
+        for (address, port, expected) in cases.iter() {
+            assert_eq!(
+                is_allowed(&destination(address, *port), &nets, &allowlist.ports),
+                *expected,
+                "{}:{}",
+                address,
+                port
+            );
+        }
+    }
+
+    #[test]
+    fn allowlist_invalid_cidr() {
+        let allowlist = EgressAllowlist {
+            cidrs: vec!["10.0.0.0/33".to_string()],
+            ..Default::default()
+        };
+        assert!(allowlist.resolve().is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use crate::LanChange;
+use chrono::{DateTime, Duration, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::{error, info};
+
+// LAN change detected by the background process monitoring
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LanEvent {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub change: LanChange,
+}
+
+// The events file is rotated past this size, keeping a single previous file
+const MAX_EVENTS_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+fn events_file_path() -> PathBuf {
+    dirs::home_dir()
+        .expect("Unable to find home directory")
+        .join(".edamame_posture_events.jsonl")
+}
+
+fn rotated_events_file_path() -> PathBuf {
+    dirs::home_dir()
+        .expect("Unable to find home directory")
+        .join(".edamame_posture_events.jsonl.1")
+}
+
+// Append the changes to the events file, one JSON object per line
+pub fn record_lan_events(changes: &[LanChange]) {
+    if changes.is_empty() {
+        return;
+    }
+    let timestamp = Utc::now();
+    let mut lines = String::new();
+    for change in changes.iter() {
+        info!("LAN event: {}", change);
+        let event = LanEvent {
+            timestamp,
+            change: change.clone(),
+        };
+        match serde_json::to_string(&event) {
+            Ok(line) => {
+                lines.push_str(&line);
+                lines.push('\n');
+            }
+            Err(e) => error!("Unable to serialize LAN event: {}", e),
+        }
+    }
+    // Only the background process writes events, rotate before opening the file
+    // as Windows does not allow to rename an open file
+    let path = events_file_path();
+    if fs::metadata(&path).is_ok_and(|metadata| metadata.len() > MAX_EVENTS_FILE_SIZE) {
+        if let Err(e) = fs::rename(&path, rotated_events_file_path()) {
+            error!("Unable to rotate LAN events: {}", e);
+        }
+    }
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| {
+            file.lock_exclusive()?;
+            file.write_all(lines.as_bytes())?;
+            file.unlock()
+        });
+    if let Err(e) = result {
+        error!("Unable to write LAN events: {}", e);
+    }
+}
+
+pub fn read_lan_events(since: Option<DateTime<Utc>>) -> Vec<LanEvent> {
+    // Older events first, from the rotated file
+    let contents = fs::read_to_string(rotated_events_file_path()).unwrap_or_default()
+        + &fs::read_to_string(events_file_path()).unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|line| serde_json::from_str::<LanEvent>(line).ok())
+        .filter(|event| match since {
+            Some(since) => event.timestamp >= since,
+            None => true,
+        })
+        .collect()
+}
+
+// Accept an RFC 3339 date or a duration relative to now (e.g. 30s, 10m, 2h, 1d)
+pub fn parse_since(since: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(since) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    let invalid = || format!("Invalid date or duration: {}", since);
+    // Split before the last character, which may not be ASCII
+    let (value, unit) = match since.char_indices().last() {
+        Some((index, _)) => since.split_at(index),
+        None => return Err(invalid()),
+    };
+    let value: i64 = value.parse().map_err(|_| invalid())?;
+    let duration = match unit {
+        "s" => Duration::try_seconds(value),
+        "m" => Duration::try_minutes(value),
+        "h" => Duration::try_hours(value),
+        "d" => Duration::try_days(value),
+        _ => return Err(invalid()),
+    }
+    .ok_or_else(invalid)?;
+    Utc::now().checked_sub_signed(duration).ok_or_else(invalid)
+}
+
+pub fn handle_lan_events(since: Option<&String>, json: bool) {
+    let since = match since.map(|since| parse_since(since)).transpose() {
+        Ok(since) => since,
+        Err(e) => {
+            eprintln!("{}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    };
+    let events = read_lan_events(since);
+    if json {
+        match serde_json::to_string_pretty(&events) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Unable to serialize LAN events: {}", e),
+        }
+        return;
+    }
+    println!("LAN events:");
+    for event in events.iter() {
+        println!(
+            "  - {} [{}] {}",
+            event.timestamp.to_rfc3339(),
+            event.change.kind(),
+            event.change
+        );
+    }
+    println!("Total events: {}", events.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn since_date() {
+        let date = parse_since("2024-03-01T12:00:00+02:00").unwrap();
+        assert_eq!(date.to_rfc3339(), "2024-03-01T10:00:00+00:00");
+    }
+
+    #[test]
+    fn since_duration() {
+        let cases = [
+            ("30s", Duration::seconds(30)),
+            ("10m", Duration::minutes(10)),
+            ("2h", Duration::hours(2)),
+            ("1d", Duration::days(1)),
+        ];
+        for (since, duration) in cases {
+            let before = Utc::now() - duration;
+            let date = parse_since(since).unwrap();
+            let after = Utc::now() - duration;
+            assert!(before <= date && date <= after, "{}", since);
+        }
+    }
+
+    #[test]
+    fn since_invalid() {
+        for since in [
+            "",
+            "10",
+            "m",
+            "10x",
+            "1.5h",
+            "10é",
+            "é",
+            "2024-03-01",
+            "999999999999d",
+        ] {
+            assert!(parse_since(since).is_err(), "{}", since);
+        }
+    }
+}
@@ -0,0 +1,734 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use sysinfo::{System, Users};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Environment variable holding the signing key when no key file is given
+const FIM_KEY_VARIABLE: &str = "EDAMAME_FIM_KEY";
+// Hash of the files and symlinks that can't be read
+const UNREADABLE: &str = "unreadable";
+// "*" does not cross directories, "**" does
+const EXCLUDE_MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+// File, symlink or directory of the baseline
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FimEntry {
+    // file, symlink or dir
+    pub kind: String,
+    // SHA-256 of the content or target of the symlink, "unreadable" when the file can't be read
+    pub hash: String,
+    pub size: u64,
+    // Permission bits, in octal
+    pub mode: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub owner: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FimManifest {
+    pub created_at: DateTime<Utc>,
+    pub host: String,
+    pub paths: Vec<String>,
+    pub exclude: Vec<String>,
+    pub entries: BTreeMap<String, FimEntry>,
+    // HMAC-SHA256 of the manifest with an empty signature, in hex
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FimChange {
+    pub path: String,
+    // added, removed, modified or unreadable (readable in the baseline, its content can't be verified)
+    pub change: String,
+    // What changed for modified entries (content, type, mode, owner)
+    pub details: Vec<String>,
+    // Current entry, or the baseline one for removed entries
+    pub entry: FimEntry,
+}
+
+pub fn default_fim_manifest_path() -> PathBuf {
+    dirs::home_dir()
+        .expect("Unable to find home directory")
+        .join(".edamame_posture_fim.json")
+}
+
+fn expand_home(path: &str) -> String {
+    match path.strip_prefix("~") {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => match dirs::home_dir() {
+            Some(home) => format!("{}{}", home.display(), rest),
+            None => path.to_string(),
+        },
+        _ => path.to_string(),
+    }
+}
+
+// System binaries and configuration, the user's cargo binaries, the runner's toolcache and the git hooks
+pub fn default_fim_paths() -> Vec<String> {
+    let mut paths = vec![
+        "/etc".to_string(),
+        "/usr/bin".to_string(),
+        "/usr/local/bin".to_string(),
+        expand_home("~/.cargo/bin"),
+    ];
+    if let Ok(toolcache) = std::env::var("RUNNER_TOOL_CACHE") {
+        if !toolcache.is_empty() {
+            paths.push(toolcache);
+        }
+    }
+    if let Ok(current_dir) = std::env::current_dir() {
+        let hooks = current_dir.join(".git").join("hooks");
+        if hooks.is_dir() {
+            paths.push(hooks.display().to_string());
+        }
+    }
+    paths
+}
+
+fn load_key(key_file: Option<&String>) -> Result<Vec<u8>, String> {
+    let key = match key_file {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read the key file {}: {}", path, e))?,
+        None => std::env::var(FIM_KEY_VARIABLE).unwrap_or_default(),
+    };
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!(
+            "A signing key is required, use --key-file or set {}",
+            FIM_KEY_VARIABLE
+        ));
+    }
+    Ok(key.as_bytes().to_vec())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                // from_str_radix would accept a sign
+                .filter(|pair| pair.len() == 2 && pair.chars().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
+
+impl FimManifest {
+    // Serialized with an empty signature, the entries are sorted by path
+    fn signed_content(&self) -> Result<Vec<u8>, String> {
+        let mut unsigned = self.clone();
+        unsigned.signature = String::new();
+        serde_json::to_vec(&unsigned)
+            .map_err(|e| format!("Unable to serialize the manifest: {}", e))
+    }
+
+    fn mac(&self, key: &[u8]) -> Result<HmacSha256, String> {
+        let mut mac = HmacSha256::new_from_slice(key).map_err(|e| format!("Invalid key: {}", e))?;
+        mac.update(&self.signed_content()?);
+        Ok(mac)
+    }
+
+    fn sign(&mut self, key: &[u8]) -> Result<(), String> {
+        self.signature = to_hex(&self.mac(key)?.finalize().into_bytes());
+        Ok(())
+    }
+
+    fn verify_signature(&self, key: &[u8]) -> Result<(), String> {
+        let signature = from_hex(&self.signature)
+            .ok_or("The manifest signature is missing or malformed".to_string())?;
+        self.mac(key)?.verify_slice(&signature).map_err(|_| {
+            "The manifest signature is invalid, the manifest was modified or the key differs"
+                .to_string()
+        })
+    }
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 65536];
+    loop {
+        let read = file.read(&mut buffer).ok()?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(unix)]
+fn ownership(metadata: &Metadata) -> (String, u32, u32) {
+    use std::os::unix::fs::MetadataExt;
+    (
+        format!("{:04o}", metadata.mode() & 0o7777),
+        metadata.uid(),
+        metadata.gid(),
+    )
+}
+
+// Permissions and ownership are not recorded on Windows
+#[cfg(not(unix))]
+fn ownership(_metadata: &Metadata) -> (String, u32, u32) {
+    ("".to_string(), 0, 0)
+}
+
+struct FimScanner {
+    users: Users,
+    exclude: Vec<glob::Pattern>,
+    entries: BTreeMap<String, FimEntry>,
+    unreadable: usize,
+}
+
+impl FimScanner {
+    fn owner(&self, uid: u32) -> String {
+        uid.to_string()
+            .parse::<sysinfo::Uid>()
+            .ok()
+            .and_then(|uid| self.users.get_user_by_id(&uid))
+            .map(|user| user.name().to_string())
+            .unwrap_or(uid.to_string())
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| pattern.matches_with(path, EXCLUDE_MATCH_OPTIONS))
+    }
+
+    // Symlinks are recorded but not followed, sockets, pipes and devices are skipped
+    fn scan(&mut self, path: &Path) {
+        let name = path.display().to_string();
+        if self.is_excluded(&name) {
+            return;
+        }
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            // Monitored paths missing on this host
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(_) => {
+                self.unreadable += 1;
+                return;
+            }
+        };
+        let file_type = metadata.file_type();
+        let (kind, hash) = if file_type.is_symlink() {
+            let target = fs::read_link(path)
+                .map(|target| target.display().to_string())
+                .unwrap_or(UNREADABLE.to_string());
+            ("symlink", target)
+        } else if file_type.is_dir() {
+            ("dir", "".to_string())
+        } else if file_type.is_file() {
+            let hash = hash_file(path).unwrap_or_else(|| {
+                self.unreadable += 1;
+                UNREADABLE.to_string()
+            });
+            ("file", hash)
+        } else {
+            return;
+        };
+        let (mode, uid, gid) = ownership(&metadata);
+        self.entries.insert(
+            name,
+            FimEntry {
+                kind: kind.to_string(),
+                hash,
+                size: if file_type.is_file() {
+                    metadata.len()
+                } else {
+                    0
+                },
+                mode,
+                uid,
+                gid,
+                owner: self.owner(uid),
+            },
+        );
+        if file_type.is_dir() {
+            match fs::read_dir(path) {
+                Ok(children) => {
+                    for child in children.filter_map(|child| child.ok()) {
+                        self.scan(&child.path());
+                    }
+                }
+                Err(_) => self.unreadable += 1,
+            }
+        }
+    }
+}
+
+// Entries under the paths, and the number of files and directories that couldn't be read
+fn scan_paths(
+    paths: &[String],
+    exclude: &[String],
+) -> Result<(BTreeMap<String, FimEntry>, usize), String> {
+    let mut patterns = Vec::new();
+    for pattern in exclude.iter() {
+        patterns.push(
+            glob::Pattern::new(pattern)
+                .map_err(|e| format!("Invalid exclude pattern {}: {}", pattern, e))?,
+        );
+    }
+    let mut scanner = FimScanner {
+        users: Users::new_with_refreshed_list(),
+        exclude: patterns,
+        entries: BTreeMap::new(),
+        unreadable: 0,
+    };
+    for path in paths.iter() {
+        scanner.scan(Path::new(path));
+    }
+    Ok((scanner.entries, scanner.unreadable))
+}
+
+fn load_fim_manifest(path: &str) -> Result<FimManifest, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read the manifest {}: {}", path, e))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| format!("Unable to parse the manifest {}: {}", path, e))
+}
+
+fn create_fim_manifest(
+    paths: &[String],
+    exclude: &[String],
+    manifest_path: &str,
+    key_file: Option<&String>,
+) -> Result<(FimManifest, usize), String> {
+    let key = load_key(key_file)?;
+    let paths: Vec<String> = paths.iter().map(|path| expand_home(path)).collect();
+    let mut exclude: Vec<String> = exclude.iter().map(|pattern| expand_home(pattern)).collect();
+    // The manifest must not hash itself
+    let absolute_manifest = std::env::current_dir()
+        .map(|current_dir| current_dir.join(manifest_path))
+        .unwrap_or(PathBuf::from(manifest_path));
+    exclude.push(glob::Pattern::escape(
+        &absolute_manifest.display().to_string(),
+    ));
+    let (entries, unreadable) = scan_paths(&paths, &exclude)?;
+    let mut manifest = FimManifest {
+        created_at: Utc::now(),
+        host: System::host_name().unwrap_or_default(),
+        paths,
+        exclude,
+        entries,
+        signature: String::new(),
+    };
+    manifest.sign(&key)?;
+    let contents = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| format!("Unable to serialize the manifest: {}", e))?;
+    fs::write(manifest_path, contents)
+        .map_err(|e| format!("Unable to write the manifest {}: {}", manifest_path, e))?;
+    Ok((manifest, unreadable))
+}
+
+fn compare_entries(baseline: &FimEntry, current: &FimEntry) -> Vec<String> {
+    let mut details = Vec::new();
+    if baseline.kind != current.kind {
+        details.push(format!("type {} -> {}", baseline.kind, current.kind));
+    } else if baseline.hash != current.hash
+        && baseline.hash != UNREADABLE
+        && current.hash != UNREADABLE
+    {
+        if current.kind == "symlink" {
+            details.push(format!("target {} -> {}", baseline.hash, current.hash));
+        } else {
+            details.push("content".to_string());
+        }
+    } else if baseline.size != current.size {
+        details.push(format!("size {} -> {}", baseline.size, current.size));
+    }
+    if baseline.mode != current.mode {
+        details.push(format!("mode {} -> {}", baseline.mode, current.mode));
+    }
+    if baseline.uid != current.uid || baseline.gid != current.gid {
+        details.push(format!(
+            "owner {} ({}:{}) -> {} ({}:{})",
+            baseline.owner, baseline.uid, baseline.gid, current.owner, current.uid, current.gid
+        ));
+    }
+    details
+}
+
+pub fn fim_changes(manifest: &FimManifest, current: &BTreeMap<String, FimEntry>) -> Vec<FimChange> {
+    let mut changes = Vec::new();
+    for (path, entry) in current.iter() {
+        match manifest.entries.get(path) {
+            None => changes.push(FimChange {
+                path: path.clone(),
+                change: "added".to_string(),
+                details: vec![],
+                entry: entry.clone(),
+            }),
+            Some(baseline) => {
+                let details = compare_entries(baseline, entry);
+                // Not a content change, but the file can no longer be verified
+                let change = if entry.hash == UNREADABLE && baseline.hash != UNREADABLE {
+                    "unreadable"
+                } else if !details.is_empty() {
+                    "modified"
+                } else {
+                    continue;
+                };
+                changes.push(FimChange {
+                    path: path.clone(),
+                    change: change.to_string(),
+                    details,
+                    entry: entry.clone(),
+                });
+            }
+        }
+    }
+    for (path, entry) in manifest.entries.iter() {
+        if !current.contains_key(path) {
+            changes.push(FimChange {
+                path: path.clone(),
+                change: "removed".to_string(),
+                details: vec![],
+                entry: entry.clone(),
+            });
+        }
+    }
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    changes
+}
+
+pub fn handle_fim_baseline(
+    paths: &[String],
+    exclude: &[String],
+    manifest_path: &str,
+    key_file: Option<&String>,
+) {
+    match create_fim_manifest(paths, exclude, manifest_path, key_file) {
+        Ok((manifest, unreadable)) => {
+            println!(
+                "File integrity baseline saved to {}: {} entries under {}",
+                manifest_path,
+                manifest.entries.len(),
+                manifest.paths.join(", ")
+            );
+            if unreadable > 0 {
+                println!(
+                    "{} file(s) or directories could not be read, run as root to cover them",
+                    unreadable
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    }
+}
+
+// Print the changes since the baseline, returns them
+pub fn handle_fim_verify(
+    manifest_path: &str,
+    key_file: Option<&String>,
+    json: bool,
+) -> Vec<FimChange> {
+    let result = load_key(key_file).and_then(|key| {
+        let manifest = load_fim_manifest(manifest_path)?;
+        manifest.verify_signature(&key)?;
+        let (current, unreadable) = scan_paths(&manifest.paths, &manifest.exclude)?;
+        Ok((manifest, current, unreadable))
+    });
+    let (manifest, current, unreadable) = match result {
+        Ok(verified) => verified,
+        Err(e) => {
+            eprintln!("{}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    };
+    let changes = fim_changes(&manifest, &current);
+    if json {
+        match serde_json::to_string_pretty(&changes) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Unable to serialize the file integrity changes: {}", e),
+        }
+        return changes;
+    }
+    println!(
+        "File changes since the baseline of {}:",
+        manifest.created_at.to_rfc3339()
+    );
+    if changes.is_empty() {
+        println!("  - None");
+    }
+    for change in changes.iter() {
+        println!(
+            "  - {} [{}{}]",
+            change.path,
+            change.change,
+            if change.details.is_empty() {
+                "".to_string()
+            } else {
+                format!(": {}", change.details.join(", "))
+            }
+        );
+        println!(
+            "    - Owner: {} ({}:{}), mode: {}",
+            change.entry.owner, change.entry.uid, change.entry.gid, change.entry.mode
+        );
+    }
+    let count = |kind: &str| {
+        changes
+            .iter()
+            .filter(|change| change.change == kind)
+            .count()
+    };
+    println!(
+        "Total: {} added, {} removed, {} modified, {} unreadable",
+        count("added"),
+        count("removed"),
+        count("modified"),
+        count("unreadable")
+    );
+    if unreadable > 0 {
+        println!(
+            "{} file(s) or directories could not be read, run as root to cover them",
+            unreadable
+        );
+    }
+    println!();
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(kind: &str, hash: &str, size: u64, mode: &str, uid: u32) -> FimEntry {
+        FimEntry {
+            kind: kind.to_string(),
+            hash: hash.to_string(),
+            size,
+            mode: mode.to_string(),
+            uid,
+            gid: uid,
+            owner: if uid == 0 { "root" } else { "runner" }.to_string(),
+        }
+    }
+
+    fn manifest(entries: &[(&str, FimEntry)]) -> FimManifest {
+        FimManifest {
+            created_at: Utc::now(),
+            host: "runner".to_string(),
+            paths: vec!["/usr/bin".to_string()],
+            exclude: vec![],
+            entries: entries
+                .iter()
+                .map(|(path, entry)| (path.to_string(), entry.clone()))
+                .collect(),
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn hex() {
+        let cases: [(&str, Option<Vec<u8>>); 8] = [
+            ("", Some(vec![])),
+            ("00ff10", Some(vec![0x00, 0xff, 0x10])),
+            ("ABcd", Some(vec![0xab, 0xcd])),
+            ("abc", None),
+            ("zz", None),
+            ("+1+1", None),
+            ("-1", None),
+            ("\u{e9}0", None),
+        ];
+        for (hex, expected) in cases.iter() {
+            assert_eq!(from_hex(hex), *expected, "{}", hex);
+        }
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(from_hex(&to_hex(&bytes)), Some(bytes));
+    }
+
+    #[test]
+    fn signature() {
+        let mut signed = manifest(&[("/usr/bin/ls", entry("file", "aa", 10, "755", 0))]);
+        signed.sign(b"secret").unwrap();
+        assert_eq!(signed.signature.len(), 64);
+        assert!(signed.verify_signature(b"secret").is_ok());
+        assert!(signed.verify_signature(b"other").is_err());
+
+        // Any change of the content invalidates the signature
+        let mut modified = signed.clone();
+        modified
+            .entries
+            .insert("/usr/bin/ls".to_string(), entry("file", "bb", 10, "755", 0));
+        assert!(modified.verify_signature(b"secret").is_err());
+        let mut modified = signed.clone();
+        modified.exclude.push("/usr/bin/*".to_string());
+        assert!(modified.verify_signature(b"secret").is_err());
+
+        for signature in ["", "zz", &signed.signature[..62]] {
+            let mut tampered = signed.clone();
+            tampered.signature = signature.to_string();
+            assert!(
+                tampered.verify_signature(b"secret").is_err(),
+                "{}",
+                signature
+            );
+        }
+    }
+
+    #[test]
+    fn changes() {
+        let baseline = manifest(&[
+            ("/usr/bin/same", entry("file", "aa", 1, "755", 0)),
+            ("/usr/bin/content", entry("file", "aa", 1, "755", 0)),
+            ("/usr/bin/size", entry("file", "aa", 1, "755", 0)),
+            ("/usr/bin/mode", entry("file", "aa", 1, "755", 0)),
+            ("/usr/bin/owner", entry("file", "aa", 1, "755", 0)),
+            ("/usr/bin/type", entry("file", "aa", 1, "755", 0)),
+            ("/usr/bin/link", entry("symlink", "/bin/a", 6, "777", 0)),
+            ("/usr/bin/removed", entry("file", "aa", 1, "755", 0)),
+        ]);
+        let current: BTreeMap<String, FimEntry> = [
+            ("/usr/bin/same", entry("file", "aa", 1, "755", 0)),
+            ("/usr/bin/content", entry("file", "bb", 1, "4755", 0)),
+            ("/usr/bin/size", entry("file", "aa", 2, "755", 0)),
+            ("/usr/bin/mode", entry("file", "aa", 1, "777", 0)),
+            ("/usr/bin/owner", entry("file", "aa", 1, "755", 1001)),
+            ("/usr/bin/type", entry("symlink", "/tmp/x", 6, "777", 0)),
+            ("/usr/bin/link", entry("symlink", "/tmp/a", 6, "777", 0)),
+            ("/usr/bin/added", entry("file", "cc", 1, "755", 0)),
+        ]
+        .iter()
+        .map(|(path, entry)| (path.to_string(), entry.clone()))
+        .collect();
+
+        let changes: Vec<(String, String, Vec<String>)> = fim_changes(&baseline, &current)
+            .into_iter()
+            .map(|change| (change.path, change.change, change.details))
+            .collect();
+        let expected = |path: &str, change: &str, details: &[&str]| {
+            (
+                format!("/usr/bin/{}", path),
+                change.to_string(),
+                details.iter().map(|detail| detail.to_string()).collect(),
+            )
+        };
+        assert_eq!(
+            changes,
+            vec![
+                expected("added", "added", &[]),
+                expected("content", "modified", &["content", "mode 755 -> 4755"]),
+                expected("link", "modified", &["target /bin/a -> /tmp/a"]),
+                expected("mode", "modified", &["mode 755 -> 777"]),
+                expected(
+                    "owner",
+                    "modified",
+                    &["owner root (0:0) -> runner (1001:1001)"]
+                ),
+                expected("removed", "removed", &[]),
+                expected("size", "modified", &["size 1 -> 2"]),
+                expected(
+                    "type",
+                    "modified",
+                    &["type file -> symlink", "mode 755 -> 777"]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unreadable_entries() {
+        let baseline = manifest(&[
+            ("/usr/bin/now", entry("file", "aa", 1, "755", 0)),
+            ("/usr/bin/now-mode", entry("file", "aa", 1, "755", 0)),
+            ("/usr/bin/before", entry("file", UNREADABLE, 1, "700", 0)),
+            (
+                "/usr/bin/before-size",
+                entry("file", UNREADABLE, 1, "700", 0),
+            ),
+            ("/usr/bin/always", entry("file", UNREADABLE, 1, "700", 0)),
+        ]);
+        let current: BTreeMap<String, FimEntry> = [
+            ("/usr/bin/now", entry("file", UNREADABLE, 1, "755", 0)),
+            ("/usr/bin/now-mode", entry("file", UNREADABLE, 1, "700", 0)),
+            ("/usr/bin/before", entry("file", "aa", 1, "700", 0)),
+            ("/usr/bin/before-size", entry("file", "aa", 2, "700", 0)),
+            ("/usr/bin/always", entry("file", UNREADABLE, 1, "700", 0)),
+        ]
+        .iter()
+        .map(|(path, entry)| (path.to_string(), entry.clone()))
+        .collect();
+
+        let changes: Vec<(String, String, Vec<String>)> = fim_changes(&baseline, &current)
+            .into_iter()
+            .map(|change| (change.path, change.change, change.details))
+            .collect();
+        // A hash that can't be compared is never reported as a content change
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    "/usr/bin/before-size".to_string(),
+                    "modified".to_string(),
+                    vec!["size 1 -> 2".to_string()]
+                ),
+                ("/usr/bin/now".to_string(), "unreadable".to_string(), vec![]),
+                (
+                    "/usr/bin/now-mode".to_string(),
+                    "unreadable".to_string(),
+                    vec!["mode 755 -> 700".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn exclude_patterns() {
+        let root = std::env::temp_dir().join(format!("edamame_fim_test_{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        for file in ["a.log", "a.bin", "sub/b.log", "sub/b.bin"] {
+            fs::write(root.join(file), file).unwrap();
+        }
+        let root_name = root.display().to_string();
+        let scan = |exclude: &[String]| {
+            let (entries, _) = scan_paths(std::slice::from_ref(&root_name), exclude).unwrap();
+            entries
+                .keys()
+                .filter_map(|path| path.strip_prefix(&format!("{}/", root_name)))
+                .map(|path| path.to_string())
+                .collect::<Vec<String>>()
+        };
+
+        // "*" stays in its directory
+        assert_eq!(
+            scan(&[format!("{}/*.log", root_name)]),
+            vec!["a.bin", "sub", "sub/b.bin", "sub/b.log"]
+        );
+        // "**" crosses directories
+        assert_eq!(
+            scan(&[format!("{}/**/*.log", root_name)]),
+            vec!["a.bin", "sub", "sub/b.bin"]
+        );
+        // An excluded directory is not walked
+        assert_eq!(
+            scan(&[format!("{}/sub", root_name)]),
+            vec!["a.bin", "a.log"]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
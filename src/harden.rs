@@ -0,0 +1,416 @@
+use crate::{load_egress_allowlist, EgressAllowlist};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// Table holding our rules, kept apart from the rest of the ruleset
+const NFT_TABLE: &str = "edamame_posture";
+
+// Interfaces of the containers and VMs hosted here, including the ones created after the hardening
+const LOCAL_BRIDGE_PATTERNS: [&str; 10] = [
+    "docker*", "br-*", "cni*", "cali*", "flannel*", "veth*", "virbr*", "lxcbr*", "lxdbr*",
+    "podman*",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HardenChange {
+    pub timestamp: DateTime<Utc>,
+    // applied or reverted
+    pub action: String,
+    pub details: String,
+}
+
+// Network hardening state, in YAML
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct HardenState {
+    pub active: bool,
+    pub allowlist: String,
+    // Ruleset in place before the first change, for display only: --revert removes our table
+    // and leaves the rest of the ruleset as it is by then
+    pub previous_ruleset: Option<String>,
+    pub changes: Vec<HardenChange>,
+}
+
+fn harden_file_path() -> PathBuf {
+    dirs::home_dir()
+        .expect("Unable to find home directory")
+        .join(".edamame_posture_harden.yaml")
+}
+
+impl HardenState {
+    pub fn load() -> Self {
+        fs::read_to_string(harden_file_path())
+            .ok()
+            .and_then(|contents| serde_yaml::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let contents = serde_yaml::to_string(self)
+            .map_err(|e| format!("Unable to serialize the hardening state: {}", e))?;
+        fs::write(harden_file_path(), contents)
+            .map_err(|e| format!("Unable to write the hardening state: {}", e))
+    }
+
+    fn record(&mut self, action: &str, details: String) {
+        self.changes.push(HardenChange {
+            timestamp: Utc::now(),
+            action: action.to_string(),
+            details,
+        });
+    }
+}
+
+fn nft(args: &[&str], input: Option<&str>) -> Result<String, String> {
+    let mut child = Command::new("nft")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Unable to run nft, is nftables installed? ({})", e))?;
+    if let Some(input) = input {
+        child
+            .stdin
+            .take()
+            .expect("nft stdin")
+            .write_all(input.as_bytes())
+            .map_err(|e| format!("Unable to write to nft: {}", e))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Unable to run nft: {}", e))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(format!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+// Overlapping entries (e.g. a resolved host inside a listed CIDR) are merged
+fn nft_set(name: &str, address_type: &str, nets: &[String]) -> String {
+    let mut set = format!(
+        "    set {} {{\n        type {}\n        flags interval\n        auto-merge\n",
+        name, address_type
+    );
+    if !nets.is_empty() {
+        set.push_str(&format!("        elements = {{ {} }}\n", nets.join(", ")));
+    }
+    set.push_str("    }\n");
+    set
+}
+
+// Bridges of the host, whatever their name
+fn local_bridges() -> Vec<String> {
+    let mut bridges: Vec<String> = fs::read_dir("/sys/class/net")
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|entry| entry.path().join("bridge").exists())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| !name.contains('"'))
+                .collect()
+        })
+        .unwrap_or_default();
+    bridges.sort();
+    bridges
+}
+
+// Egress allowed to the listed networks and ports only, replacing a previous version of our table
+pub fn egress_ruleset(nets: &[IpNet], ports: &[u16], bridges: &[String]) -> String {
+    let mut v4: Vec<String> = Vec::new();
+    let mut v6: Vec<String> = Vec::new();
+    for net in nets.iter() {
+        match net {
+            IpNet::V4(net) => v4.push(net.to_string()),
+            IpNet::V6(net) => v6.push(net.to_string()),
+        }
+    }
+    v4.sort();
+    v4.dedup();
+    v6.sort();
+    v6.dedup();
+
+    let mut ruleset = format!(
+        "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n",
+        table = NFT_TABLE
+    );
+    ruleset.push_str(&nft_set("allowed_v4", "ipv4_addr", &v4));
+    ruleset.push_str(&nft_set("allowed_v6", "ipv6_addr", &v6));
+    ruleset.push_str(&egress_chain("output", ports, bridges));
+    // Traffic of the containers and VMs hosted here is forwarded, it does not go through output
+    ruleset.push_str(&egress_chain("forward", ports, bridges));
+    ruleset.push_str("}\n");
+    ruleset
+}
+
+fn egress_chain(hook: &str, ports: &[u16], bridges: &[String]) -> String {
+    let mut chain = format!(
+        "    chain {hook} {{\n        type filter hook {hook} priority 0; policy drop;\n",
+        hook = hook
+    );
+    if hook == "output" {
+        chain.push_str("        oif \"lo\" accept\n");
+    }
+    // Replies to inbound connections and connections opened before the hardening
+    chain.push_str("        ct state established,related accept\n");
+    // Traffic to the containers and VMs hosted here stays on the host
+    for name in LOCAL_BRIDGE_PATTERNS
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .chain(bridges.iter().cloned())
+    {
+        chain.push_str(&format!("        oifname \"{}\" accept\n", name));
+    }
+    // Inbound connections to published ports, translated to a container or VM address
+    if hook == "forward" {
+        chain.push_str("        ct status dnat accept\n");
+    }
+    chain.push_str("        ip daddr @allowed_v4 accept\n");
+    chain.push_str("        ip6 daddr @allowed_v6 accept\n");
+    // The ports are allowed to any destination, as in the egress report
+    if !ports.is_empty() {
+        let ports = ports
+            .iter()
+            .map(|port| port.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        chain.push_str(&format!("        tcp dport {{ {} }} accept\n", ports));
+        chain.push_str(&format!("        udp dport {{ {} }} accept\n", ports));
+    }
+    // IPv6 needs neighbor discovery to reach anything, it is never forwarded
+    if hook == "output" {
+        chain.push_str(
+            "        icmpv6 type { nd-neighbor-solicit, nd-neighbor-advert, nd-router-solicit } accept\n",
+        );
+    }
+    chain.push_str("        counter drop\n");
+    chain.push_str("    }\n");
+    chain
+}
+
+fn resolve_allowlist(path: &str) -> Result<(EgressAllowlist, Vec<IpNet>), String> {
+    let allowlist = load_egress_allowlist(path)?;
+    let nets = allowlist.resolve()?;
+    Ok((allowlist, nets))
+}
+
+pub fn handle_harden_network_allow(path: &str, dry_run: bool) {
+    if !cfg!(target_os = "linux") {
+        eprintln!("Network hardening is only supported on Linux (nftables)");
+        // Exit with an error code
+        std::process::exit(1);
+    }
+    let (allowlist, nets) = match resolve_allowlist(path) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            eprintln!("{}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    };
+    let ruleset = egress_ruleset(&nets, &allowlist.ports, &local_bridges());
+    if dry_run {
+        print!("{}", ruleset);
+        return;
+    }
+
+    let mut state = HardenState::load();
+    // Keep the original ruleset when the allowlist is applied again
+    if state.previous_ruleset.is_none() {
+        match nft(&["list", "ruleset"], None) {
+            Ok(previous) => state.previous_ruleset = Some(previous),
+            Err(e) => {
+                eprintln!("Unable to record the current ruleset: {}", e);
+                // Exit with an error code
+                std::process::exit(1);
+            }
+        }
+    }
+    let details = format!(
+        "egress allowlist {}: {} hosts, {} networks, ports [{}]",
+        path,
+        allowlist.hosts.len(),
+        nets.len(),
+        allowlist
+            .ports
+            .iter()
+            .map(|port| port.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+    // Save the state first, so that an applied table can always be reverted
+    let before = state.clone();
+    state.active = true;
+    state.allowlist = path.to_string();
+    state.record("applied", details.clone());
+    if let Err(e) = state.save() {
+        eprintln!("{}", e);
+        // Exit with an error code
+        std::process::exit(1);
+    }
+    if let Err(e) = nft(&["-f", "-"], Some(&ruleset)) {
+        eprintln!("Unable to apply the egress allowlist: {}", e);
+        // The ruleset is applied atomically, nothing changed
+        if let Err(e) = before.save() {
+            eprintln!("{}", e);
+        }
+        // Exit with an error code
+        std::process::exit(1);
+    }
+    println!("Applied {}", details);
+    if !allowlist.ports.is_empty() {
+        println!(
+            "Warning: ports [{}] are allowed to any destination, any process can send data out through them",
+            allowlist
+                .ports
+                .iter()
+                .map(|port| port.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+}
+
+pub fn handle_harden_network_revert() {
+    let mut state = HardenState::load();
+    if !state.active {
+        println!("No network hardening to revert");
+        return;
+    }
+    // Only remove our table, the rest of the ruleset may have changed since the hardening,
+    // declaring the table first makes the deletion succeed if it is already gone
+    let revert = format!(
+        "table inet {table}\ndelete table inet {table}\n",
+        table = NFT_TABLE
+    );
+    if let Err(e) = nft(&["-f", "-"], Some(&revert)) {
+        eprintln!("Unable to remove the egress allowlist: {}", e);
+        // Exit with an error code
+        std::process::exit(1);
+    }
+    state.active = false;
+    state.previous_ruleset = None;
+    state.record("reverted", format!("table {} removed", NFT_TABLE));
+    if let Err(e) = state.save() {
+        eprintln!("{}", e);
+        // Exit with an error code
+        std::process::exit(1);
+    }
+    println!("Egress allowlist removed");
+}
+
+pub fn show_harden_status() {
+    let state = HardenState::load();
+    if state.changes.is_empty() {
+        return;
+    }
+    println!("Network hardening:");
+    if state.active {
+        println!("  - Active, egress allowlist: {}", state.allowlist);
+        if let Some(previous) = &state.previous_ruleset {
+            println!(
+                "  - Ruleset before the hardening: {} lines, recorded in {}",
+                previous.lines().count(),
+                harden_file_path().display()
+            );
+        }
+    } else {
+        println!("  - Not active");
+    }
+    println!("  - Changes:");
+    for change in state.changes.iter() {
+        println!(
+            "    - {} [{}] {}",
+            change.timestamp.to_rfc3339(),
+            change.action,
+            change.details
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ruleset_sets() {
+        let nets: Vec<IpNet> = ["10.0.0.0/8", "1.1.1.1/32", "10.0.0.0/8", "2606:4700::/32"]
+            .iter()
+            .map(|net| net.parse().unwrap())
+            .collect();
+        let ruleset = egress_ruleset(&nets, &[], &[]);
+        // Our table only, replaced as a whole
+        assert!(ruleset.starts_with(
+            "table inet edamame_posture\ndelete table inet edamame_posture\ntable inet edamame_posture {\n"
+        ));
+        assert!(ruleset.contains("        elements = { 1.1.1.1/32, 10.0.0.0/8 }\n"));
+        assert!(ruleset.contains("        elements = { 2606:4700::/32 }\n"));
+        assert!(!ruleset.contains("dport"));
+    }
+
+    #[test]
+    fn ruleset_empty_allowlist() {
+        let ruleset = egress_ruleset(&[], &[], &[]);
+        // nft refuses an empty elements list
+        assert!(!ruleset.contains("elements"));
+        assert_eq!(ruleset.matches("policy drop;").count(), 2);
+    }
+
+    #[test]
+    fn ruleset_chains() {
+        let ruleset = egress_ruleset(&[], &[53, 443], &["br0".to_string()]);
+        for hook in ["output", "forward"] {
+            let start = ruleset.find(&format!("    chain {} {{\n", hook)).unwrap();
+            let chain = &ruleset[start..];
+            let chain = &chain[..chain.find("    }\n").unwrap()];
+            assert!(chain.contains(&format!(
+                "        type filter hook {} priority 0; policy drop;\n",
+                hook
+            )));
+            assert!(chain.contains("        ct state established,related accept\n"));
+            assert!(chain.contains("        ip daddr @allowed_v4 accept\n"));
+            assert!(chain.contains("        ip6 daddr @allowed_v6 accept\n"));
+            assert!(chain.contains("        tcp dport { 53, 443 } accept\n"));
+            assert!(chain.contains("        udp dport { 53, 443 } accept\n"));
+            assert!(chain.ends_with("        counter drop\n"));
+            // Loopback and neighbor discovery only exist for locally generated traffic
+            assert_eq!(chain.contains("oif \"lo\" accept"), hook == "output");
+            assert_eq!(chain.contains("nd-neighbor-solicit"), hook == "output");
+            // Containers and VMs hosted here
+            assert!(chain.contains("        oifname \"docker*\" accept\n"));
+            assert!(chain.contains("        oifname \"br0\" accept\n"));
+            assert_eq!(
+                chain.contains("        ct status dnat accept\n"),
+                hook == "forward"
+            );
+        }
+        assert!(ruleset.ends_with("    }\n}\n"));
+    }
+
+    // Needs nft and root (nft -c still talks to the kernel): cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn ruleset_accepted_by_nft() {
+        let nets: Vec<IpNet> = ["10.0.0.0/8", "10.1.2.3/32", "2606:4700::/32"]
+            .iter()
+            .map(|net| net.parse().unwrap())
+            .collect();
+        for ruleset in [
+            egress_ruleset(&nets, &[53, 443], &["br0".to_string()]),
+            egress_ruleset(&[], &[], &[]),
+        ] {
+            if let Err(e) = nft(&["-c", "-f", "-"], Some(&ruleset)) {
+                panic!("{}\n{}", e, ruleset);
+            }
+        }
+    }
+}
@@ -0,0 +1,589 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use sysinfo::{Disks, Networks, System};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DiskInfo {
+    pub name: String,
+    pub mount_point: String,
+    pub file_system: String,
+    pub total_space: u64,
+    pub available_space: u64,
+    pub removable: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FirewallInfo {
+    // nftables, iptables, pf, Application Firewall, Windows Firewall or none
+    pub backend: String,
+    // ufw or firewalld managing the backend, if any
+    pub frontend: String,
+    pub active: Option<bool>,
+    // Not readable without root privileges on most systems
+    pub rules: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LoggedUser {
+    pub name: String,
+    pub terminal: String,
+    pub host: String,
+    pub since: String,
+}
+
+// Host facts relevant to the security posture
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SystemReport {
+    pub host_name: String,
+    pub os_name: String,
+    pub os_version: String,
+    pub kernel_version: String,
+    pub architecture: String,
+    pub boot_time: String,
+    pub uptime: u64,
+    pub cpus: usize,
+    pub total_memory: u64,
+    pub used_memory: u64,
+    pub total_swap: u64,
+    pub used_swap: u64,
+    pub disks: Vec<DiskInfo>,
+    pub network_interfaces: Vec<String>,
+    // Hypervisor name, "none" on bare metal
+    pub virtualization: String,
+    // Container runtime, "none" outside of a container
+    pub container: String,
+    pub secure_boot: String,
+    pub selinux: String,
+    pub apparmor: String,
+    pub firewall: FirewallInfo,
+    pub logged_in_users: Vec<LoggedUser>,
+    // Raw hardware information, only with --verbose
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hardware_details: Option<String>,
+}
+
+// Standard output of a command, None if it cannot run or fails
+pub fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    match Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).to_string())
+        }
+        _ => None,
+    }
+}
+
+fn unknown() -> String {
+    "unknown".to_string()
+}
+
+// "who" output: "user  pts/0  2024-05-01 10:00 (10.0.0.1)"
+fn parse_who(output: &str) -> Vec<LoggedUser> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (line, host) = match line.split_once('(') {
+                Some((line, host)) => (line, host.trim_end_matches(')').to_string()),
+                None => (line, "".to_string()),
+            };
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() < 2 {
+                return None;
+            }
+            Some(LoggedUser {
+                name: tokens[0].to_string(),
+                terminal: tokens[1].to_string(),
+                host,
+                since: tokens[2..].join(" "),
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn read(path: &str) -> Option<String> {
+        fs::read_to_string(path)
+            .ok()
+            .map(|contents| contents.trim().to_string())
+    }
+
+    pub fn virtualization() -> String {
+        if let Some(virt) = command_output("systemd-detect-virt", &["--vm"]) {
+            return virt.trim().to_string();
+        }
+        // systemd-detect-virt exits with an error on bare metal, check the DMI strings
+        let dmi = ["sys_vendor", "product_name", "bios_vendor"]
+            .iter()
+            .filter_map(|file| read(&format!("/sys/class/dmi/id/{}", file)))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let known = [
+            ("KVM", "kvm"),
+            ("QEMU", "qemu"),
+            ("VMware", "vmware"),
+            ("VirtualBox", "oracle"),
+            ("Xen", "xen"),
+            ("Amazon EC2", "amazon"),
+            ("Google", "google"),
+            ("Virtual Machine", "microsoft"),
+            ("Parallels", "parallels"),
+        ];
+        if let Some((_, name)) = known.iter().find(|(pattern, _)| dmi.contains(pattern)) {
+            return name.to_string();
+        }
+        let cpuinfo = read("/proc/cpuinfo").unwrap_or_default();
+        if cpuinfo.lines().any(|line| {
+            line.starts_with("flags") && line.split_whitespace().any(|flag| flag == "hypervisor")
+        }) {
+            return "unknown hypervisor".to_string();
+        }
+        "none".to_string()
+    }
+
+    pub fn container() -> String {
+        if Path::new("/.dockerenv").exists() {
+            return "docker".to_string();
+        }
+        if Path::new("/run/.containerenv").exists() {
+            return "podman".to_string();
+        }
+        if std::env::var("KUBERNETES_SERVICE_HOST").is_ok() {
+            return "kubernetes".to_string();
+        }
+        let cgroup = read("/proc/1/cgroup").unwrap_or_default();
+        for (pattern, name) in [
+            ("kubepods", "kubernetes"),
+            ("docker", "docker"),
+            ("containerd", "containerd"),
+            ("lxc", "lxc"),
+        ] {
+            if cgroup.contains(pattern) {
+                return name.to_string();
+            }
+        }
+        match command_output("systemd-detect-virt", &["--container"]) {
+            Some(container) => container.trim().to_string(),
+            None => "none".to_string(),
+        }
+    }
+
+    pub fn secure_boot() -> String {
+        if !Path::new("/sys/firmware/efi").exists() {
+            return "unsupported (legacy BIOS boot)".to_string();
+        }
+        // 4 bytes of attributes followed by the value
+        let efivar = "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+        match fs::read(efivar) {
+            Ok(data) if data.len() >= 5 => {
+                if data[4] == 1 {
+                    "enabled".to_string()
+                } else {
+                    "disabled".to_string()
+                }
+            }
+            _ => match command_output("mokutil", &["--sb-state"]) {
+                Some(state) if state.contains("enabled") => "enabled".to_string(),
+                Some(state) if state.contains("disabled") => "disabled".to_string(),
+                _ => unknown(),
+            },
+        }
+    }
+
+    pub fn selinux() -> String {
+        match read("/sys/fs/selinux/enforce").as_deref() {
+            Some("1") => "enforcing".to_string(),
+            Some("0") => "permissive".to_string(),
+            _ if Path::new("/etc/selinux/config").exists() => "disabled".to_string(),
+            _ => "not installed".to_string(),
+        }
+    }
+
+    pub fn apparmor() -> String {
+        if read("/sys/module/apparmor/parameters/enabled").as_deref() != Some("Y") {
+            return "not enabled".to_string();
+        }
+        // Only readable by root
+        match read("/sys/kernel/security/apparmor/profiles") {
+            Some(profiles) => {
+                let enforced = profiles
+                    .lines()
+                    .filter(|line| line.ends_with("(enforce)"))
+                    .count();
+                let complain = profiles
+                    .lines()
+                    .filter(|line| line.ends_with("(complain)"))
+                    .count();
+                format!(
+                    "enabled ({} profiles in enforce mode, {} in complain mode)",
+                    enforced, complain
+                )
+            }
+            None => "enabled".to_string(),
+        }
+    }
+
+    // Rules of the nftables ruleset, without the table, chain and set declarations
+    fn nft_rule_count(ruleset: &str) -> usize {
+        ruleset
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| {
+                !line.is_empty()
+                    && !line.ends_with('{')
+                    && *line != "}"
+                    && !line.starts_with("type ")
+                    && !line.starts_with("policy ")
+                    && !line.starts_with("flags ")
+                    && !line.starts_with("elements ")
+            })
+            .count()
+    }
+
+    pub fn firewall() -> FirewallInfo {
+        let mut firewall = FirewallInfo::default();
+        if let Some(status) = command_output("ufw", &["status"]) {
+            firewall.frontend = "ufw".to_string();
+            firewall.active = Some(status.contains("Status: active"));
+        } else if let Some(state) = command_output("firewall-cmd", &["--state"]) {
+            firewall.frontend = "firewalld".to_string();
+            firewall.active = Some(state.trim() == "running");
+        }
+        let iptables_rules = command_output("iptables-save", &[])
+            .map(|rules| rules.lines().filter(|line| line.starts_with("-A ")).count());
+        match command_output("nft", &["list", "ruleset"]) {
+            // iptables-nft rules also show in the nftables ruleset
+            Some(ruleset) if !ruleset.trim().is_empty() => {
+                firewall.backend = "nftables".to_string();
+                firewall.rules = Some(nft_rule_count(&ruleset));
+            }
+            _ => match iptables_rules {
+                Some(rules) => {
+                    firewall.backend = "iptables".to_string();
+                    firewall.rules = Some(rules);
+                }
+                None if Path::new("/proc/net/ip_tables_names").exists() => {
+                    firewall.backend = "iptables".to_string();
+                }
+                None if which("nft") => firewall.backend = "nftables".to_string(),
+                None => firewall.backend = "none".to_string(),
+            },
+        }
+        if firewall.active.is_none() {
+            firewall.active = firewall.rules.map(|rules| rules > 0);
+        }
+        firewall
+    }
+
+    fn which(program: &str) -> bool {
+        std::env::var("PATH")
+            .unwrap_or_default()
+            .split(':')
+            .any(|dir| Path::new(dir).join(program).exists())
+    }
+
+    pub fn logged_in_users() -> Vec<LoggedUser> {
+        parse_who(&command_output("who", &[]).unwrap_or_default())
+    }
+
+    pub fn hardware_details() -> Option<String> {
+        fs::read_to_string("/proc/cpuinfo").ok()
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::*;
+
+    pub fn virtualization() -> String {
+        match command_output("sysctl", &["-n", "kern.hv_vmm_present"]) {
+            Some(present) if present.trim() == "1" => "unknown hypervisor".to_string(),
+            Some(_) => "none".to_string(),
+            None => unknown(),
+        }
+    }
+
+    pub fn container() -> String {
+        "none".to_string()
+    }
+
+    // Apple silicon and T2 machines always boot with secure boot, its level is not exposed to users
+    pub fn secure_boot() -> String {
+        unknown()
+    }
+
+    pub fn selinux() -> String {
+        "not applicable".to_string()
+    }
+
+    pub fn apparmor() -> String {
+        "not applicable".to_string()
+    }
+
+    pub fn firewall() -> FirewallInfo {
+        let mut firewall = FirewallInfo {
+            backend: "Application Firewall".to_string(),
+            ..Default::default()
+        };
+        if let Some(state) = command_output(
+            "/usr/libexec/ApplicationFirewall/socketfilterfw",
+            &["--getglobalstate"],
+        ) {
+            firewall.active = Some(state.contains("enabled"));
+        }
+        // pf rules need root privileges
+        if let Some(rules) = command_output("pfctl", &["-sr"]) {
+            firewall.frontend = "pf".to_string();
+            firewall.rules = Some(rules.lines().filter(|line| !line.trim().is_empty()).count());
+        }
+        firewall
+    }
+
+    pub fn logged_in_users() -> Vec<LoggedUser> {
+        parse_who(&command_output("who", &[]).unwrap_or_default())
+    }
+
+    pub fn hardware_details() -> Option<String> {
+        command_output("system_profiler", &["SPHardwareDataType"])
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::*;
+
+    fn powershell(command: &str) -> Option<String> {
+        command_output("powershell", &["-NoProfile", "-Command", command])
+            .map(|output| output.trim().to_string())
+    }
+
+    pub fn virtualization() -> String {
+        match powershell("(Get-CimInstance Win32_ComputerSystem).HypervisorPresent") {
+            Some(present) if present == "True" => {
+                powershell("(Get-CimInstance Win32_ComputerSystem).Model")
+                    .unwrap_or("unknown hypervisor".to_string())
+            }
+            Some(_) => "none".to_string(),
+            None => unknown(),
+        }
+    }
+
+    pub fn container() -> String {
+        match std::env::var("USERNAME").as_deref() {
+            Ok("ContainerAdministrator") | Ok("ContainerUser") => "windows container".to_string(),
+            _ => "none".to_string(),
+        }
+    }
+
+    pub fn secure_boot() -> String {
+        match powershell("Confirm-SecureBootUEFI").as_deref() {
+            Some("True") => "enabled".to_string(),
+            Some("False") => "disabled".to_string(),
+            _ => unknown(),
+        }
+    }
+
+    pub fn selinux() -> String {
+        "not applicable".to_string()
+    }
+
+    pub fn apparmor() -> String {
+        "not applicable".to_string()
+    }
+
+    pub fn firewall() -> FirewallInfo {
+        let mut firewall = FirewallInfo {
+            backend: "Windows Firewall".to_string(),
+            ..Default::default()
+        };
+        if let Some(state) =
+            command_output("netsh", &["advfirewall", "show", "allprofiles", "state"])
+        {
+            firewall.active = Some(state.lines().any(|line| line.trim().ends_with("ON")));
+        }
+        firewall.rules = powershell("(Get-NetFirewallRule -Enabled True | Measure-Object).Count")
+            .and_then(|count| count.parse().ok());
+        firewall
+    }
+
+    // "query user" output: " USERNAME  SESSIONNAME  ID  STATE  IDLE TIME  LOGON TIME"
+    pub fn logged_in_users() -> Vec<LoggedUser> {
+        command_output("query", &["user"])
+            .unwrap_or_default()
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let tokens: Vec<&str> = line.trim_start_matches('>').split_whitespace().collect();
+                if tokens.len() < 6 {
+                    return None;
+                }
+                Some(LoggedUser {
+                    name: tokens[0].to_string(),
+                    terminal: tokens[1].to_string(),
+                    host: "".to_string(),
+                    since: tokens[tokens.len() - 2..].join(" "),
+                })
+            })
+            .collect()
+    }
+
+    pub fn hardware_details() -> Option<String> {
+        powershell("Get-CimInstance -ClassName Win32_ComputerSystem | Format-List Manufacturer,Model,SystemType")
+    }
+}
+
+pub fn collect_system_report(verbose: bool) -> SystemReport {
+    let mut sys = System::new_all();
+    sys.refresh_all();
+    sysinfo::set_open_files_limit(0);
+
+    let boot_time = DateTime::<Utc>::from_timestamp(System::boot_time() as i64, 0)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default();
+    let disks = Disks::new_with_refreshed_list()
+        .iter()
+        .map(|disk| DiskInfo {
+            name: disk.name().to_string_lossy().to_string(),
+            mount_point: disk.mount_point().display().to_string(),
+            file_system: disk.file_system().to_string_lossy().to_string(),
+            total_space: disk.total_space(),
+            available_space: disk.available_space(),
+            removable: disk.is_removable(),
+        })
+        .collect();
+    let mut network_interfaces: Vec<String> = Networks::new_with_refreshed_list()
+        .keys()
+        .cloned()
+        .collect();
+    network_interfaces.sort();
+
+    SystemReport {
+        host_name: System::host_name().unwrap_or_default(),
+        os_name: System::name().unwrap_or_default(),
+        os_version: System::os_version().unwrap_or_default(),
+        kernel_version: System::kernel_version().unwrap_or_default(),
+        architecture: System::cpu_arch().unwrap_or_default(),
+        boot_time,
+        uptime: System::uptime(),
+        cpus: sys.cpus().len(),
+        total_memory: sys.total_memory(),
+        used_memory: sys.used_memory(),
+        total_swap: sys.total_swap(),
+        used_swap: sys.used_swap(),
+        disks,
+        network_interfaces,
+        virtualization: platform::virtualization(),
+        container: platform::container(),
+        secure_boot: platform::secure_boot(),
+        selinux: platform::selinux(),
+        apparmor: platform::apparmor(),
+        firewall: platform::firewall(),
+        logged_in_users: platform::logged_in_users(),
+        hardware_details: if verbose {
+            platform::hardware_details()
+        } else {
+            None
+        },
+    }
+}
+
+fn format_uptime(seconds: u64) -> String {
+    format!(
+        "{}d {}h {}m",
+        seconds / 86400,
+        (seconds % 86400) / 3600,
+        (seconds % 3600) / 60
+    )
+}
+
+pub fn print_system_report(report: &SystemReport) {
+    println!("System information:");
+    println!("  - Host name:       {}", report.host_name);
+    println!(
+        "  - OS:              {} {}",
+        report.os_name, report.os_version
+    );
+    println!("  - Kernel version:  {}", report.kernel_version);
+    println!("  - Architecture:    {}", report.architecture);
+    println!("  - Boot time:       {}", report.boot_time);
+    println!("  - Uptime:          {}", format_uptime(report.uptime));
+    println!("  - CPUs:            {}", report.cpus);
+    println!(
+        "  - Memory:          {} / {} bytes used",
+        report.used_memory, report.total_memory
+    );
+    println!(
+        "  - Swap:            {} / {} bytes used",
+        report.used_swap, report.total_swap
+    );
+    println!("  - Disks:");
+    for disk in report.disks.iter() {
+        println!(
+            "    - {} on {} ({}): {} / {} bytes available{}",
+            disk.name,
+            disk.mount_point,
+            disk.file_system,
+            disk.available_space,
+            disk.total_space,
+            if disk.removable { ", removable" } else { "" }
+        );
+    }
+    println!(
+        "  - Network interfaces: {}",
+        report.network_interfaces.join(", ")
+    );
+    println!();
+
+    println!("Security:");
+    println!("  - Virtualization:  {}", report.virtualization);
+    println!("  - Container:       {}", report.container);
+    println!("  - Secure boot:     {}", report.secure_boot);
+    println!("  - SELinux:         {}", report.selinux);
+    println!("  - AppArmor:        {}", report.apparmor);
+    let firewall = &report.firewall;
+    println!(
+        "  - Firewall:        {}{}, {}, {} rules",
+        firewall.backend,
+        if firewall.frontend.is_empty() {
+            "".to_string()
+        } else {
+            format!(" ({})", firewall.frontend)
+        },
+        match firewall.active {
+            Some(true) => "active",
+            Some(false) => "inactive",
+            None => "state unknown",
+        },
+        firewall
+            .rules
+            .map(|rules| rules.to_string())
+            .unwrap_or("unknown number of".to_string())
+    );
+    println!("  - Logged in users:");
+    if report.logged_in_users.is_empty() {
+        println!("    - None");
+    }
+    for user in report.logged_in_users.iter() {
+        println!(
+            "    - {} on {} since {}{}",
+            user.name,
+            user.terminal,
+            user.since,
+            if user.host.is_empty() {
+                "".to_string()
+            } else {
+                format!(" from {}", user.host)
+            }
+        );
+    }
+    println!();
+
+    if let Some(details) = &report.hardware_details {
+        println!("Hardware details:");
+        println!("{}", details);
+    }
+}
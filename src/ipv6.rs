@@ -0,0 +1,121 @@
+use crate::is_link_local6;
+use ipnet::Ipv6Net;
+use std::net::Ipv6Addr;
+use std::process::Command;
+use tracing::info;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ipv6Neighbor {
+    pub ip: Ipv6Addr,
+    pub mac: String,
+    // Not reported on all platforms
+    pub interface: Option<String>,
+}
+
+fn is_mac(token: &str) -> bool {
+    let parts: Vec<&str> = token.split([':', '-']).collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|part| !part.is_empty() && u8::from_str_radix(part, 16).is_ok())
+}
+
+// Parse "ip -6 neigh" (Linux), "ndp -an" (macOS) or "netsh interface ipv6 show neighbors" (Windows)
+fn parse_neighbors(output: &str) -> Vec<Ipv6Neighbor> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let (ip, scope) = tokens.iter().find_map(|token| {
+                let (address, scope) = match token.split_once('%') {
+                    Some((address, scope)) => (address, Some(scope.to_string())),
+                    None => (*token, None),
+                };
+                address.parse::<Ipv6Addr>().ok().map(|ip| (ip, scope))
+            })?;
+            let mac = tokens.iter().find(|token| is_mac(token))?;
+            let mac = mac.replace('-', ":").to_lowercase();
+            // Multicast entries are not devices
+            if ip.is_multicast() || mac.starts_with("33:33") || mac == "00:00:00:00:00:00" {
+                return None;
+            }
+            let interface = tokens
+                .iter()
+                .position(|token| *token == "dev")
+                .and_then(|index| tokens.get(index + 1))
+                .map(|name| name.to_string())
+                .or(scope);
+            Some(Ipv6Neighbor { ip, mac, interface })
+        })
+        .collect()
+}
+
+pub fn ipv6_neighbor_table() -> Vec<Ipv6Neighbor> {
+    let output = if cfg!(target_os = "linux") {
+        Command::new("ip").args(["-6", "neigh", "show"]).output()
+    } else if cfg!(target_os = "macos") {
+        Command::new("ndp").arg("-an").output()
+    } else {
+        Command::new("netsh")
+            .args(["interface", "ipv6", "show", "neighbors"])
+            .output()
+    };
+    match output {
+        Ok(output) => parse_neighbors(&String::from_utf8_lossy(&output.stdout)),
+        Err(e) => {
+            info!("Unable to read the IPv6 neighbor table: {}", e);
+            vec![]
+        }
+    }
+}
+
+// Ping the all-nodes multicast address so that the neighbors show up in the NDP table
+pub fn multicast_ping6(interface: &str) {
+    let result = if cfg!(target_os = "linux") {
+        Command::new("ping")
+            .args(["-6", "-c", "2", "-w", "3", "-I", interface, "ff02::1"])
+            .output()
+    } else if cfg!(target_os = "macos") {
+        Command::new("ping6")
+            .args(["-c", "2", "-I", interface, "ff02::1"])
+            .output()
+    } else {
+        Command::new("ping")
+            .args(["-6", "-n", "2", &format!("ff02::1%{}", interface)])
+            .output()
+    };
+    if let Err(e) = result {
+        info!("Unable to ping ff02::1 on {}: {}", interface, e);
+    }
+}
+
+// Neighbors of the selected IPv6 subnets, link-local neighbors are kept for the selected interfaces
+pub fn discover_ipv6_neighbors(
+    subnets: &[(Ipv6Addr, u8, String)],
+    active: bool,
+) -> Vec<Ipv6Neighbor> {
+    let mut interfaces: Vec<&String> = subnets.iter().map(|(_, _, name)| name).collect();
+    interfaces.dedup();
+    if active {
+        for interface in interfaces.iter() {
+            multicast_ping6(interface);
+        }
+    }
+    let nets: Vec<Ipv6Net> = subnets
+        .iter()
+        .filter_map(|(ip, prefix, _)| Ipv6Net::new(*ip, *prefix).ok())
+        .collect();
+    ipv6_neighbor_table()
+        .into_iter()
+        .filter(|neighbor| {
+            if nets.iter().any(|net| net.contains(&neighbor.ip)) {
+                return true;
+            }
+            is_link_local6(&neighbor.ip)
+                && match &neighbor.interface {
+                    Some(name) => interfaces.contains(&name),
+                    None => true,
+                }
+        })
+        .collect()
+}
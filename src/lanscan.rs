@@ -0,0 +1,590 @@
+use edamame_core::api::api_lanscan::{
+    LANScanAPI, LANScanAPIDeviceInfo, LANScanAPIPortInfo, LANScanAPIVulnerabilityInfo,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Serializable copies of the core LAN scan records, used for exports and comparisons
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LanVulnerability {
+    pub name: String,
+    pub description: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LanPort {
+    pub port: u16,
+    pub protocol: String,
+    pub service: String,
+    pub banner: String,
+    pub vulnerabilities: Vec<LanVulnerability>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LanDevice {
+    pub hostname: String,
+    pub custom_name: String,
+    pub ip_address: String,
+    pub ip_addresses: Vec<String>,
+    pub mac_address: String,
+    pub mac_addresses: Vec<String>,
+    pub mdns_services: Vec<String>,
+    pub os_name: String,
+    pub os_version: String,
+    pub device_vendor: String,
+    pub device_type: String,
+    pub criticality: String,
+    pub has_edamame: bool,
+    pub edamame_version: String,
+    pub open_ports: Vec<LanPort>,
+    pub dismissed_ports: Vec<u16>,
+    pub vulnerabilities: Vec<LanVulnerability>,
+    pub active: bool,
+    pub added: bool,
+    pub activated: bool,
+    pub deactivated: bool,
+    pub deleted: bool,
+    pub no_icmp: bool,
+    pub non_std_ports: bool,
+    pub last_time_seen: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LanInterface {
+    pub name: String,
+    pub ip: String,
+    pub prefix: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LanInventory {
+    pub last_scan: String,
+    // Gateway address, empty if unknown
+    #[serde(default)]
+    pub gateway: String,
+    pub interfaces: Vec<LanInterface>,
+    pub devices: Vec<LanDevice>,
+}
+
+impl From<&LANScanAPIVulnerabilityInfo> for LanVulnerability {
+    fn from(vulnerability: &LANScanAPIVulnerabilityInfo) -> Self {
+        LanVulnerability {
+            name: vulnerability.name.clone(),
+            description: vulnerability.description.clone(),
+        }
+    }
+}
+
+impl From<&LANScanAPIPortInfo> for LanPort {
+    fn from(port: &LANScanAPIPortInfo) -> Self {
+        LanPort {
+            port: port.port,
+            protocol: port.protocol.clone(),
+            service: port.service.clone(),
+            banner: port.banner.clone(),
+            vulnerabilities: port.vulnerabilities.iter().map(|v| v.into()).collect(),
+        }
+    }
+}
+
+impl From<&LANScanAPIDeviceInfo> for LanDevice {
+    fn from(device: &LANScanAPIDeviceInfo) -> Self {
+        LanDevice {
+            hostname: device.hostname.clone(),
+            custom_name: device.custom_name.clone(),
+            ip_address: device.ip_address.clone(),
+            ip_addresses: device.ip_addresses.clone(),
+            mac_address: device.mac_address.clone(),
+            mac_addresses: device.mac_addresses.clone(),
+            mdns_services: device.mdns_services.clone(),
+            os_name: device.os_name.clone(),
+            os_version: device.os_version.clone(),
+            device_vendor: device.device_vendor.clone(),
+            device_type: device.device_type.clone(),
+            criticality: device.criticality.clone(),
+            has_edamame: device.has_edamame,
+            edamame_version: device.edamame_version.clone(),
+            open_ports: device.open_ports.iter().map(|p| p.into()).collect(),
+            dismissed_ports: device.dismissed_ports.clone(),
+            vulnerabilities: device.vulnerabilities.iter().map(|v| v.into()).collect(),
+            active: device.active,
+            added: device.added,
+            activated: device.activated,
+            deactivated: device.deactivated,
+            deleted: device.deleted,
+            no_icmp: device.no_icmp,
+            non_std_ports: device.non_std_ports,
+            last_time_seen: device.last_time_seen.clone(),
+        }
+    }
+}
+
+impl From<&LANScanAPI> for LanInventory {
+    fn from(devices: &LANScanAPI) -> Self {
+        LanInventory {
+            last_scan: devices.last_scan.clone(),
+            gateway: devices.network.network.wifi_gateway.clone(),
+            // Interfaces are in the form (ip, subnet, name)
+            interfaces: devices
+                .network
+                .network
+                .interfaces
+                .iter()
+                .map(|interface| LanInterface {
+                    name: interface.2.clone(),
+                    ip: interface.0.clone(),
+                    prefix: interface.1,
+                })
+                .collect(),
+            devices: devices.devices.iter().map(|d| d.into()).collect(),
+        }
+    }
+}
+
+impl LanDevice {
+    // All the MAC addresses of the device, lower case
+    pub fn macs(&self) -> Vec<String> {
+        let mut macs = self.mac_addresses.clone();
+        if !self.mac_address.is_empty() && !macs.contains(&self.mac_address) {
+            macs.insert(0, self.mac_address.clone());
+        }
+        macs.iter().map(|mac| mac.to_lowercase()).collect()
+    }
+
+    // All the IP addresses of the device
+    pub fn ips(&self) -> Vec<String> {
+        let mut ips = self.ip_addresses.clone();
+        if !self.ip_address.is_empty() && !ips.contains(&self.ip_address) {
+            ips.insert(0, self.ip_address.clone());
+        }
+        ips
+    }
+}
+
+// Which devices to display and how
+#[derive(Clone, Debug, Default)]
+pub struct LanDisplayOptions {
+    // Show every port with its details and the criticality factors
+    pub verbose: bool,
+    // Only show the device with this IP or MAC address, in verbose mode
+    pub details: Option<String>,
+    pub criticality: Vec<String>,
+    pub vendor: Option<String>,
+    pub device_type: Vec<String>,
+}
+
+impl LanDisplayOptions {
+    pub fn is_filtered(&self) -> bool {
+        self.details.is_some()
+            || !self.criticality.is_empty()
+            || self.vendor.is_some()
+            || !self.device_type.is_empty()
+    }
+
+    pub fn matches(&self, device: &LanDevice) -> bool {
+        if let Some(address) = &self.details {
+            if !device_has_address(device, address) {
+                return false;
+            }
+        }
+        if !self.criticality.is_empty()
+            && !self
+                .criticality
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(&device.criticality))
+        {
+            return false;
+        }
+        // Vendor names vary, match on a substring
+        if let Some(vendor) = &self.vendor {
+            if !device
+                .device_vendor
+                .to_lowercase()
+                .contains(&vendor.to_lowercase())
+            {
+                return false;
+            }
+        }
+        if !self.device_type.is_empty()
+            && !self
+                .device_type
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(&device.device_type))
+        {
+            return false;
+        }
+        true
+    }
+}
+
+// IP addresses are compared parsed, so that e.g. "fe80::A" matches "fe80::a"
+fn device_has_address(device: &LanDevice, address: &str) -> bool {
+    match address.parse::<IpAddr>() {
+        Ok(address) => device
+            .ips()
+            .iter()
+            .any(|ip| ip.parse::<IpAddr>() == Ok(address)),
+        Err(_) => device.macs().contains(&address.to_lowercase()),
+    }
+}
+
+// The core does not expose the reasoning behind the criticality, these are only the
+// observed facts about the device
+pub fn observed_facts(device: &LanDevice) -> Vec<String> {
+    let mut factors = Vec::new();
+    let vulnerable_ports: Vec<String> = device
+        .open_ports
+        .iter()
+        .filter(|port| !port.vulnerabilities.is_empty())
+        .map(|port| format!("{} ({})", port.port, port.vulnerabilities.len()))
+        .collect();
+    if !vulnerable_ports.is_empty() {
+        factors.push(format!(
+            "known vulnerabilities on ports {}",
+            vulnerable_ports.join(", ")
+        ));
+    }
+    if !device.vulnerabilities.is_empty() {
+        factors.push(format!(
+            "{} known vulnerabilities on the device",
+            device.vulnerabilities.len()
+        ));
+    }
+    if device.non_std_ports {
+        factors.push("open ports unusual for its device type".to_string());
+    }
+    if !device.open_ports.is_empty() {
+        factors.push(format!("{} open ports", device.open_ports.len()));
+    }
+    if device.no_icmp {
+        factors.push("does not answer ICMP".to_string());
+    }
+    if device.has_edamame {
+        factors.push("managed by EDAMAME".to_string());
+    }
+    if factors.is_empty() {
+        factors.push("no open port or known vulnerability".to_string());
+    }
+    factors
+}
+
+pub fn print_lan_device(device: &LanDevice, verbose: bool) {
+    println!("  - '{}'", device.hostname);
+    if !device.custom_name.is_empty() {
+        println!("    - Name: {}", device.custom_name);
+    }
+    println!("    - Type: {}", device.device_type);
+    println!("    - Vendor: {}", device.device_vendor);
+    println!("    - IPs: {}", device.ip_addresses.join(", "));
+    println!("    - MACs: {}", device.mac_addresses.join(", "));
+    println!("    - Has EDAMAME: {}", device.has_edamame);
+    println!("    - Criticality: {}", device.criticality);
+    if !verbose {
+        println!(
+            "    - Open ports: {}",
+            device
+                .open_ports
+                .iter()
+                .map(|port| port.port.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        return;
+    }
+
+    println!(
+        "    - Observed facts: {}",
+        observed_facts(device).join(", ")
+    );
+    if !device.os_name.is_empty() {
+        println!("    - OS: {} {}", device.os_name, device.os_version);
+    }
+    if !device.mdns_services.is_empty() {
+        println!("    - mDNS services: {}", device.mdns_services.join(", "));
+    }
+    if !device.last_time_seen.is_empty() {
+        println!("    - Last seen: {}", device.last_time_seen);
+    }
+    println!("    - Open ports:");
+    for port in device.open_ports.iter() {
+        println!(
+            "      - {}/{}: {}{}",
+            port.port,
+            port.protocol,
+            port.service,
+            if port.banner.is_empty() {
+                "".to_string()
+            } else {
+                format!(" ({})", port.banner)
+            }
+        );
+        for vulnerability in port.vulnerabilities.iter() {
+            println!(
+                "        - Vulnerability {}: {}",
+                vulnerability.name, vulnerability.description
+            );
+        }
+    }
+    if !device.dismissed_ports.is_empty() {
+        println!(
+            "    - Dismissed ports: {}",
+            device
+                .dismissed_ports
+                .iter()
+                .map(|port| port.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+    for vulnerability in device.vulnerabilities.iter() {
+        println!(
+            "    - Vulnerability {}: {}",
+            vulnerability.name, vulnerability.description
+        );
+    }
+}
+
+pub fn print_lan_inventory(inventory: &LanInventory, display: &LanDisplayOptions) {
+    println!("LAN scan completed at: {}", inventory.last_scan);
+
+    let mut displayed = 0;
+    for device in inventory.devices.iter() {
+        if !display.matches(device) {
+            continue;
+        }
+        displayed += 1;
+        print_lan_device(device, display.verbose || display.details.is_some());
+    }
+    if display.is_filtered() {
+        println!("Displayed devices: {}", displayed);
+    }
+    println!(
+        "Total devices: {}, {} devices have EDAMAME, {} devices are highly critical",
+        inventory.devices.len(),
+        inventory
+            .devices
+            .iter()
+            .filter(|device| device.has_edamame)
+            .count(),
+        inventory
+            .devices
+            .iter()
+            .filter(|device| device.criticality == "High")
+            .count()
+    );
+    println!();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    NmapXml,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "json" => Some(ExportFormat::Json),
+            "csv" => Some(ExportFormat::Csv),
+            "nmap-xml" => Some(ExportFormat::NmapXml),
+            _ => None,
+        }
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn vulnerabilities_field(vulnerabilities: &[LanVulnerability]) -> String {
+    vulnerabilities
+        .iter()
+        .map(|v| v.name.clone())
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+// One row per open port, devices without open ports get a single row with empty port columns
+fn to_csv(inventory: &LanInventory) -> String {
+    let mut csv = String::from(
+        "hostname,custom_name,ip_addresses,mac_addresses,vendor,type,os_name,os_version,criticality,has_edamame,edamame_version,mdns_services,device_vulnerabilities,last_time_seen,port,protocol,service,banner,port_vulnerabilities\n",
+    );
+    for device in inventory.devices.iter() {
+        let device_fields = [
+            device.hostname.clone(),
+            device.custom_name.clone(),
+            device.ips().join(";"),
+            device.macs().join(";"),
+            device.device_vendor.clone(),
+            device.device_type.clone(),
+            device.os_name.clone(),
+            device.os_version.clone(),
+            device.criticality.clone(),
+            device.has_edamame.to_string(),
+            device.edamame_version.clone(),
+            device.mdns_services.join(";"),
+            vulnerabilities_field(&device.vulnerabilities),
+            device.last_time_seen.clone(),
+        ];
+        let port_rows: Vec<[String; 5]> = if device.open_ports.is_empty() {
+            vec![Default::default()]
+        } else {
+            device
+                .open_ports
+                .iter()
+                .map(|port| {
+                    [
+                        port.port.to_string(),
+                        port.protocol.clone(),
+                        port.service.clone(),
+                        port.banner.clone(),
+                        vulnerabilities_field(&port.vulnerabilities),
+                    ]
+                })
+                .collect()
+        };
+        for port_fields in port_rows.iter() {
+            let row = device_fields
+                .iter()
+                .chain(port_fields.iter())
+                .map(|field| csv_field(field))
+                .collect::<Vec<String>>()
+                .join(",");
+            csv.push_str(&row);
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+// Subset of the nmap XML output format understood by the usual nmap parsers
+fn to_nmap_xml(inventory: &LanInventory) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<!DOCTYPE nmaprun>\n");
+    xml.push_str(&format!(
+        "<nmaprun scanner=\"edamame_posture\" args=\"edamame_posture lanscan\" start=\"{}\" startstr=\"{}\" version=\"{}\" xmloutputversion=\"1.05\">\n",
+        now,
+        xml_escape(&inventory.last_scan),
+        env!("CARGO_PKG_VERSION")
+    ));
+    for device in inventory.devices.iter() {
+        xml.push_str("<host>\n");
+        let state = if device.active { "up" } else { "down" };
+        xml.push_str(&format!(
+            "<status state=\"{}\" reason=\"edamame\"/>\n",
+            state
+        ));
+        for ip in device.ips().iter() {
+            let addrtype = if ip.contains(':') { "ipv6" } else { "ipv4" };
+            xml.push_str(&format!(
+                "<address addr=\"{}\" addrtype=\"{}\"/>\n",
+                xml_escape(ip),
+                addrtype
+            ));
+        }
+        for mac in device.macs().iter() {
+            xml.push_str(&format!(
+                "<address addr=\"{}\" addrtype=\"mac\" vendor=\"{}\"/>\n",
+                xml_escape(&mac.to_uppercase()),
+                xml_escape(&device.device_vendor)
+            ));
+        }
+        xml.push_str("<hostnames>\n");
+        if !device.hostname.is_empty() {
+            xml.push_str(&format!(
+                "<hostname name=\"{}\" type=\"PTR\"/>\n",
+                xml_escape(&device.hostname)
+            ));
+        }
+        if !device.custom_name.is_empty() {
+            xml.push_str(&format!(
+                "<hostname name=\"{}\" type=\"user\"/>\n",
+                xml_escape(&device.custom_name)
+            ));
+        }
+        xml.push_str("</hostnames>\n");
+        xml.push_str("<ports>\n");
+        for port in device.open_ports.iter() {
+            let protocol = if port.protocol.is_empty() {
+                "tcp".to_string()
+            } else {
+                port.protocol.to_lowercase()
+            };
+            xml.push_str(&format!(
+                "<port protocol=\"{}\" portid=\"{}\">\n",
+                xml_escape(&protocol),
+                port.port
+            ));
+            xml.push_str("<state state=\"open\" reason=\"edamame\"/>\n");
+            xml.push_str(&format!(
+                "<service name=\"{}\" product=\"{}\" method=\"probed\"/>\n",
+                xml_escape(&port.service),
+                xml_escape(&port.banner)
+            ));
+            for vulnerability in port.vulnerabilities.iter() {
+                xml.push_str(&format!(
+                    "<script id=\"vulners\" output=\"{}: {}\"/>\n",
+                    xml_escape(&vulnerability.name),
+                    xml_escape(&vulnerability.description)
+                ));
+            }
+            xml.push_str("</port>\n");
+        }
+        xml.push_str("</ports>\n");
+        if !device.os_name.is_empty() {
+            xml.push_str(&format!(
+                "<os>\n<osmatch name=\"{} {}\" accuracy=\"100\"/>\n</os>\n",
+                xml_escape(&device.os_name),
+                xml_escape(&device.os_version)
+            ));
+        }
+        xml.push_str("</host>\n");
+    }
+    let up = inventory.devices.iter().filter(|d| d.active).count();
+    xml.push_str(&format!(
+        "<runstats>\n<finished time=\"{}\" timestr=\"{}\" exit=\"success\"/>\n<hosts up=\"{}\" down=\"{}\" total=\"{}\"/>\n</runstats>\n",
+        now,
+        xml_escape(&inventory.last_scan),
+        up,
+        inventory.devices.len() - up,
+        inventory.devices.len()
+    ));
+    xml.push_str("</nmaprun>\n");
+    xml
+}
+
+pub fn export_lan_inventory(
+    inventory: &LanInventory,
+    format: ExportFormat,
+    path: &str,
+) -> Result<(), String> {
+    let contents = match format {
+        ExportFormat::Json => serde_json::to_string_pretty(inventory)
+            .map_err(|e| format!("Unable to serialize LAN inventory: {}", e))?,
+        ExportFormat::Csv => to_csv(inventory),
+        ExportFormat::NmapXml => to_nmap_xml(inventory),
+    };
+    fs::write(path, contents).map_err(|e| format!("Unable to write {}: {}", path, e))
+}
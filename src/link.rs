@@ -0,0 +1,368 @@
+use crate::command_output;
+use edamame_core::api::api_lanscan::LANScanAPINetwork;
+
+// Connection of the host, as detected from the system
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkInfo {
+    pub interface: String,
+    pub is_ethernet: bool,
+    pub is_wifi: bool,
+    pub is_vpn: bool,
+    pub is_tethering: bool,
+    pub is_mobile: bool,
+    pub ssid: String,
+    pub bssid: String,
+    pub gateway: String,
+    pub ip: String,
+    pub submask: String,
+    pub broadcast: String,
+}
+
+impl LinkInfo {
+    pub fn kind(&self) -> String {
+        let mut kinds = Vec::new();
+        if self.is_wifi {
+            kinds.push("Wi-Fi");
+        }
+        if self.is_ethernet {
+            kinds.push("Ethernet");
+        }
+        if self.is_tethering {
+            kinds.push("tethering");
+        }
+        if self.is_mobile {
+            kinds.push("mobile");
+        }
+        if self.is_vpn {
+            kinds.push("VPN");
+        }
+        if kinds.is_empty() {
+            "unknown".to_string()
+        } else {
+            kinds.join(", ")
+        }
+    }
+
+    pub fn to_network(&self) -> LANScanAPINetwork {
+        LANScanAPINetwork {
+            interfaces: vec![],
+            scanned_interfaces: vec![],
+            is_ethernet: self.is_ethernet,
+            is_wifi: self.is_wifi,
+            is_vpn: self.is_vpn,
+            is_tethering: self.is_tethering,
+            is_mobile: self.is_mobile,
+            wifi_bssid: self.bssid.clone(),
+            wifi_ip: self.ip.clone(),
+            wifi_submask: self.submask.clone(),
+            wifi_gateway: self.gateway.clone(),
+            wifi_broadcast: self.broadcast.clone(),
+            wifi_name: self.ssid.clone(),
+            wifi_ipv6: "".to_string(),
+        }
+    }
+}
+
+// Value of a "key: value" or "key : value" line
+fn field(output: &str, key: &str) -> String {
+    output
+        .lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(name, _)| name.trim() == key)
+        .map(|(_, value)| value.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn fill_addresses(link: &mut LinkInfo) {
+    let interfaces = if_addrs::get_if_addrs().unwrap_or_default();
+    if let Some(addr) = interfaces
+        .iter()
+        .filter(|interface| interface.name == link.interface)
+        .find_map(|interface| match &interface.addr {
+            if_addrs::IfAddr::V4(addr) => Some(addr),
+            _ => None,
+        })
+    {
+        link.ip = addr.ip.to_string();
+        link.submask = addr.netmask.to_string();
+        link.broadcast = addr
+            .broadcast
+            .map(|broadcast| broadcast.to_string())
+            .unwrap_or_default();
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::*;
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use std::path::Path;
+
+    // Default routes as (interface, gateway), by increasing metric
+    fn default_routes() -> Vec<(String, String)> {
+        let contents = fs::read_to_string("/proc/net/route").unwrap_or_default();
+        let mut routes: Vec<(u32, String, String)> = contents
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() < 7 || fields[1] != "00000000" {
+                    return None;
+                }
+                // Addresses are printed as host words, their bytes are in network order
+                let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+                let metric = fields[6].parse().unwrap_or(0);
+                Some((
+                    metric,
+                    fields[0].to_string(),
+                    Ipv4Addr::from(gateway.to_ne_bytes()).to_string(),
+                ))
+            })
+            .collect();
+        routes.sort();
+        routes
+            .into_iter()
+            .map(|(_, interface, gateway)| (interface, gateway))
+            .collect()
+    }
+
+    fn sysfs(interface: &str, file: &str) -> String {
+        fs::read_to_string(format!("/sys/class/net/{}/{}", interface, file))
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    }
+
+    fn devtype(interface: &str) -> String {
+        field(&sysfs(interface, "uevent").replace('=', ":"), "DEVTYPE")
+    }
+
+    fn driver(interface: &str) -> String {
+        fs::read_link(format!("/sys/class/net/{}/device/driver", interface))
+            .ok()
+            .and_then(|path| {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            })
+            .unwrap_or_default()
+    }
+
+    // Wireless extensions or nl80211
+    fn is_wireless(interface: &str) -> bool {
+        let base = Path::new("/sys/class/net").join(interface);
+        base.join("wireless").exists()
+            || base.join("phy80211").exists()
+            || devtype(interface) == "wlan"
+    }
+
+    // tun/tap, WireGuard and point-to-point links
+    fn is_vpn(interface: &str) -> bool {
+        let base = Path::new("/sys/class/net").join(interface);
+        base.join("tun_flags").exists()
+            || devtype(interface) == "wireguard"
+            // ARPHRD_NONE and ARPHRD_PPP
+            || matches!(sysfs(interface, "type").as_str(), "65534" | "512")
+            || ["wg", "tun", "tap", "ppp", "ipsec", "vpn"]
+                .iter()
+                .any(|prefix| interface.starts_with(prefix))
+    }
+
+    // USB tethering through a phone
+    fn is_tethering(interface: &str) -> bool {
+        matches!(
+            driver(interface).as_str(),
+            "rndis_host" | "cdc_ether" | "cdc_ncm" | "ipheth" | "cdc_eem"
+        )
+    }
+
+    fn is_mobile(interface: &str) -> bool {
+        devtype(interface) == "wwan"
+            || matches!(driver(interface).as_str(), "qmi_wwan" | "cdc_mbim")
+    }
+
+    fn is_up(interface: &str) -> bool {
+        matches!(sysfs(interface, "operstate").as_str(), "up" | "unknown")
+    }
+
+    pub fn detect_link(preferred: Option<&str>) -> LinkInfo {
+        let routes = default_routes();
+        let interface = match preferred {
+            Some(interface) => interface.to_string(),
+            // The default route goes through the VPN when it is active, use the physical link
+            None => routes
+                .iter()
+                .find(|(interface, _)| !is_vpn(interface))
+                .or(routes.first())
+                .map(|(interface, _)| interface.clone())
+                .unwrap_or_default(),
+        };
+        let mut link = LinkInfo {
+            interface: interface.clone(),
+            gateway: routes
+                .iter()
+                .find(|(name, _)| *name == interface)
+                .map(|(_, gateway)| gateway.clone())
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+
+        link.is_wifi = is_wireless(&interface);
+        link.is_tethering = is_tethering(&interface);
+        link.is_mobile = is_mobile(&interface);
+        link.is_ethernet = !interface.is_empty()
+            && !link.is_wifi
+            && !link.is_tethering
+            && !link.is_mobile
+            && !is_vpn(&interface);
+        // Only a VPN carrying the default route, a VPN that merely is up does not change the connection
+        link.is_vpn = routes
+            .first()
+            .is_some_and(|(name, _)| is_vpn(name) && is_up(name));
+
+        if link.is_wifi {
+            let output = command_output("iw", &["dev", &interface, "link"]).unwrap_or_default();
+            link.ssid = field(&output, "SSID");
+            link.bssid = output
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("Connected to "))
+                .and_then(|rest| rest.split_whitespace().next())
+                .unwrap_or_default()
+                .to_string();
+            // Wireless extensions fallback
+            if link.ssid.is_empty() {
+                link.ssid = command_output("iwgetid", &[&interface, "-r"])
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string();
+            }
+            if link.bssid.is_empty() {
+                link.bssid = command_output("iwgetid", &[&interface, "-a", "-r"])
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string();
+            }
+        }
+        fill_addresses(&mut link);
+        link
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::*;
+
+    // Hardware ports as (port name, device)
+    fn hardware_ports() -> Vec<(String, String)> {
+        let output = command_output("networksetup", &["-listallhardwareports"]).unwrap_or_default();
+        output
+            .split("\n\n")
+            .filter_map(|block| {
+                let port = field(block, "Hardware Port");
+                let device = field(block, "Device");
+                if port.is_empty() || device.is_empty() {
+                    None
+                } else {
+                    Some((port, device))
+                }
+            })
+            .collect()
+    }
+
+    pub fn detect_link(preferred: Option<&str>) -> LinkInfo {
+        let route = command_output("route", &["-n", "get", "default"]).unwrap_or_default();
+        let route_interface = field(&route, "interface");
+        let interface = preferred
+            .map(|interface| interface.to_string())
+            .unwrap_or(route_interface.clone());
+        let mut link = LinkInfo {
+            interface: interface.clone(),
+            gateway: if interface == route_interface {
+                field(&route, "gateway")
+            } else {
+                "".to_string()
+            },
+            ..Default::default()
+        };
+
+        let port = hardware_ports()
+            .into_iter()
+            .find(|(_, device)| *device == interface)
+            .map(|(port, _)| port)
+            .unwrap_or_default();
+        link.is_wifi = port == "Wi-Fi" || port == "AirPort";
+        link.is_tethering = port.contains("iPhone") || port.contains("Bluetooth PAN");
+        link.is_ethernet = !interface.is_empty() && !link.is_wifi && !link.is_tethering;
+        // Only a VPN carrying the default route
+        link.is_vpn = route_interface.starts_with("utun")
+            || route_interface.starts_with("ppp")
+            || route_interface.starts_with("ipsec");
+        if link.is_wifi {
+            let output = command_output("networksetup", &["-getairportnetwork", &interface])
+                .unwrap_or_default();
+            link.ssid = field(&output, "Current Wi-Fi Network");
+        }
+        fill_addresses(&mut link);
+        link
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::*;
+
+    pub fn detect_link(preferred: Option<&str>) -> LinkInfo {
+        let mut link = LinkInfo::default();
+        // Default routes: "0.0.0.0  0.0.0.0  <gateway>  <interface address>  <metric>"
+        let routes = command_output("route", &["print", "0.0.0.0"]).unwrap_or_default();
+        let default_route = routes
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+            .filter(|fields| fields.len() >= 5 && fields[0] == "0.0.0.0" && fields[1] == "0.0.0.0")
+            .min_by_key(|fields| fields[4].parse::<u32>().unwrap_or(u32::MAX));
+        if let Some(fields) = &default_route {
+            link.gateway = fields[2].to_string();
+        }
+        // Interface of the default route, found by its address
+        let interfaces = if_addrs::get_if_addrs().unwrap_or_default();
+        let route_interface = default_route
+            .and_then(|fields| {
+                interfaces
+                    .iter()
+                    .find(|interface| interface.ip().to_string() == fields[3])
+            })
+            .map(|interface| interface.name.clone())
+            .unwrap_or_default();
+        link.interface = preferred
+            .map(|interface| interface.to_string())
+            .unwrap_or(route_interface.clone());
+
+        let wlan = command_output("netsh", &["wlan", "show", "interfaces"]).unwrap_or_default();
+        let wlan_interface = field(&wlan, "Name");
+        if field(&wlan, "State") == "connected"
+            && !link.interface.is_empty()
+            && link.interface.eq_ignore_ascii_case(&wlan_interface)
+        {
+            link.is_wifi = true;
+            link.ssid = field(&wlan, "SSID");
+            // The BSSID contains colons, take everything after the first separator
+            link.bssid = wlan
+                .lines()
+                .find(|line| line.trim().starts_with("BSSID"))
+                .and_then(|line| line.split_once(" : "))
+                .map(|(_, bssid)| bssid.trim().to_string())
+                .unwrap_or_default();
+        }
+        // Only a VPN carrying the default route
+        let name = route_interface.to_lowercase();
+        link.is_vpn = name.contains("vpn") || name.contains("wireguard") || name.contains("tap");
+        link.is_ethernet = !link.interface.is_empty() && !link.is_wifi && !link.is_vpn;
+        fill_addresses(&mut link);
+        link
+    }
+}
+
+// Link of the preferred interface, or of the default route
+pub fn detect_link(preferred: Option<&str>) -> LinkInfo {
+    platform::detect_link(preferred)
+}
@@ -6,14 +6,50 @@ mod commands;
 use commands::*;
 mod background;
 use background::*;
-use clap::{arg, Command};
+mod wait;
+use wait::*;
+mod network;
+use network::*;
+mod lanscan;
+use lanscan::*;
+mod baseline;
+use baseline::*;
+mod policy;
+use policy::*;
+mod events;
+use events::*;
+mod scan;
+use scan::*;
+mod topology;
+use topology::*;
+mod database;
+use database::*;
+mod ipv6;
+use ipv6::*;
+mod link;
+use link::*;
+mod metadata;
+use metadata::*;
+mod host;
+use host::*;
+mod sockets;
+use sockets::*;
+mod egress;
+use egress::*;
+mod harden;
+use harden::*;
+mod processes;
+use processes::*;
+mod memaccess;
+use memaccess::*;
+mod fim;
+use fim::*;
+
+use clap::{arg, Arg, ArgAction, ArgMatches, Command};
 use edamame_core::api::api_core::*;
-use edamame_core::api::api_lanscan::*;
 use edamame_core::api::api_score::*;
 use envcrypt::envc;
 use machine_uid;
-use std::thread::sleep;
-use std::time::Duration;
 
 fn run() {
     let mut device = DeviceInfoAPI {
@@ -32,26 +68,28 @@ fn run() {
         // Debug logging
         //std::env::set_var("EDAMAME_LOG_LEVEL", "debug");
 
-        if args.len() == 7 {
+        if let Some(params) = BackgroundParams::from_args(&args[2..]) {
+            // Hold the instance lock for the lifetime of the background process
+            let _instance_lock = match InstanceLock::acquire() {
+                Some(lock) => lock,
+                None => {
+                    eprintln!("Another background process is already running, exiting...");
+                    // Exit with an error code
+                    std::process::exit(1);
+                }
+            };
+
             // Save state within the child for unix
             #[cfg(unix)]
             {
-                let state = State {
-                    pid: Some(std::process::id()),
-                    handle: None,
-                    is_success: false,
-                    connected_domain: args[3].clone(),
-                    connected_user: args[2].clone(),
-                    last_network_activity: "".to_string(),
-                };
+                let state = params.to_state(std::process::id(), None);
                 state.save();
             }
 
             // Set device ID
             // Prefix it with the machine uid
             let machine_uid = machine_uid::get().unwrap_or("".to_string());
-            device.device_id =
-                (machine_uid + "/" + args[5].clone().to_string().as_str()).to_string();
+            device.device_id = (machine_uid + "/" + params.device_id.as_str()).to_string();
 
             // Reporting is on community is off
             initialize(
@@ -70,14 +108,7 @@ fn run() {
                 std::process::exit(1);
             }
 
-            let lan_scanning = if args[6] == "true" { true } else { false };
-
-            background_process(
-                args[2].clone(),
-                args[3].clone(),
-                args[4].clone(),
-                lan_scanning,
-            );
+            background_process(params);
         } else {
             eprintln!("Invalid arguments for background process: {:?}", args);
             // Exit with an error code
@@ -106,13 +137,331 @@ fn run() {
     }
 }
 
+fn wait_arg() -> Arg {
+    Arg::new("wait")
+        .long("wait")
+        .value_name("TIMEOUT")
+        .help("Wait until the background process is connected to the domain (timeout in seconds, defaults to 600)")
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_missing_value("600")
+        .value_parser(clap::value_parser!(u64))
+}
+
+fn network_args() -> [Arg; 6] {
+    [
+        Arg::new("interface")
+            .long("interface")
+            .value_name("NAME")
+            .help("Network interface to scan (repeatable or comma separated, defaults to autodetection)")
+            .action(ArgAction::Append)
+            .value_delimiter(','),
+        Arg::new("cidr")
+            .long("cidr")
+            .value_name("CIDR")
+            .help("IPv4 or IPv6 subnet to scan, must be reachable from a local interface (repeatable or comma separated)")
+            .action(ArgAction::Append)
+            .value_delimiter(','),
+        Arg::new("exclude-cidr")
+            .long("exclude-cidr")
+            .value_name("CIDR")
+            .help("Subnet to exclude from the scan (repeatable or comma separated)")
+            .action(ArgAction::Append)
+            .value_delimiter(','),
+        Arg::new("ipv6")
+            .long("ipv6")
+            .help("Discover IPv6 neighbors (NDP and multicast ping) on the selected interfaces")
+            .action(ArgAction::SetTrue),
+        Arg::new("gateway")
+            .long("gateway")
+            .value_name("IP")
+            .help("Gateway address, skips the gateway detection"),
+        Arg::new("gateway-timeout")
+            .long("gateway-timeout")
+            .value_name("SECONDS")
+            .help("Timeout of the gateway detection before scanning in degraded mode (defaults to 120 seconds)")
+            .value_parser(clap::value_parser!(u64)),
+    ]
+}
+
+fn gateway_options(matches: &ArgMatches) -> GatewayOptions {
+    let mut options = GatewayOptions {
+        gateway: matches.get_one::<String>("gateway").cloned(),
+        ..GatewayOptions::default()
+    };
+    if let Some(timeout) = matches.get_one::<u64>("gateway-timeout") {
+        options.timeout = *timeout;
+    }
+    options
+}
+
+fn network_selection(matches: &ArgMatches) -> NetworkSelection {
+    let values = |id: &str| -> Vec<String> {
+        matches
+            .get_many::<String>(id)
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default()
+    };
+    NetworkSelection {
+        interfaces: values("interface"),
+        cidrs: values("cidr"),
+        exclude_cidrs: values("exclude-cidr"),
+        ipv6: matches.get_flag("ipv6"),
+    }
+}
+
+fn scan_args() -> [Arg; 4] {
+    [
+        Arg::new("scan-profile")
+            .long("scan-profile")
+            .value_name("PROFILE")
+            .help("LAN scan intensity: passive (neighbor table only), light (rate limited sweep) or full (defaults to full)")
+            .value_parser(["passive", "light", "full"]),
+        Arg::new("max-pps")
+            .long("max-pps")
+            .value_name("PPS")
+            .help("Maximum probes per second of the light profile (defaults to 50)")
+            .value_parser(clap::value_parser!(u32)),
+        Arg::new("scan-max-duration")
+            .long("scan-max-duration")
+            .value_name("SECONDS")
+            .help("Report partial results after this duration, the full profile scan keeps running in the core")
+            .value_parser(clap::value_parser!(u64)),
+        Arg::new("ports")
+            .long("ports")
+            .value_name("PORTS")
+            .help("Ports probed by the light profile (e.g. 22,80,8000-8100)"),
+    ]
+}
+
+fn scan_options(matches: &ArgMatches) -> ScanOptions {
+    let mut options = ScanOptions::default();
+    if let Some(profile) = matches.get_one::<String>("scan-profile") {
+        options.profile = ScanProfile::parse(profile).unwrap_or(ScanProfile::Full);
+    }
+    if let Some(max_pps) = matches.get_one::<u32>("max-pps") {
+        options.max_pps = *max_pps;
+    }
+    if let Some(max_duration) = matches.get_one::<u64>("scan-max-duration") {
+        options.max_duration = *max_duration;
+    }
+    options.ports = matches.get_one::<String>("ports").cloned();
+    if let Err(e) = options.validate() {
+        eprintln!("{}", e);
+        // Exit with an error code
+        std::process::exit(1);
+    }
+    options
+}
+
+fn metadata_address_arg() -> Arg {
+    Arg::new("metadata-address")
+        .long("metadata-address")
+        .value_name("ADDRESS")
+        .help("Address of the cloud metadata service, host or host:port (defaults to 169.254.169.254)")
+        .default_value(DEFAULT_METADATA_ADDRESS)
+}
+
+fn fim_manifest_arg() -> Arg {
+    arg!(--manifest <FILE> "Manifest file (defaults to ~/.edamame_posture_fim.json)")
+        .required(false)
+}
+
+fn fim_key_file_arg() -> Arg {
+    arg!(--"key-file" <FILE> "File holding the key signing the manifest (defaults to the EDAMAME_FIM_KEY environment variable)")
+        .required(false)
+}
+
+fn fim_manifest_path(matches: &ArgMatches) -> String {
+    matches
+        .get_one::<String>("manifest")
+        .cloned()
+        .unwrap_or(default_fim_manifest_path().display().to_string())
+}
+
 fn run_base() {
     let matches = Command::new("edamame_posture")
         .version("1.0")
         .author("Frank Lyonnet")
         .about("CLI interface to edamame_core")
-        .subcommand(Command::new("score").about("Get score information"))
-        .subcommand(Command::new("lanscan").about("Performs a LAN scan"))
+        .subcommand(
+            Command::new("score")
+                .about("Get score information")
+                .arg(arg!(--"check-metadata" "Check whether the cloud metadata endpoints are reachable"))
+                .arg(metadata_address_arg())
+                .arg(arg!(--"check-memory-access" "Report the processes reading the memory of other processes")),
+        )
+        .subcommand(
+            Command::new("lanscan")
+                .about("Performs a LAN scan")
+                .args(network_args())
+                .args(scan_args())
+                .arg(arg!(--output <FILE> "Export the LAN inventory to this file").required(false))
+                .arg(
+                    arg!(--format <FORMAT> "Export format")
+                        .required(false)
+                        .value_parser(["json", "csv", "nmap-xml"])
+                        .default_value("json"),
+                )
+                .arg(
+                    arg!(--graph <FORMAT> "Output the network topology in this format")
+                        .required(false)
+                        .value_parser(["dot", "mermaid", "json-graph"]),
+                )
+                .arg(arg!(--"graph-output" <FILE> "Write the network topology to this file instead of the standard output").required(false))
+                .arg(arg!(--baseline <FILE> "Compare the scan with this inventory, created if missing").required(false))
+                .arg(arg!(--"update-baseline" "Save the scan as the new baseline after the comparison"))
+                .arg(
+                    Arg::new("fail-on")
+                        .long("fail-on")
+                        .value_name("CHANGES")
+                        .help("Fail when the baseline comparison finds these changes (comma separated)")
+                        .value_delimiter(',')
+                        .action(ArgAction::Append)
+                        .value_parser(LAN_CHANGE_KINDS),
+                )
+                .arg(arg!(--policy <FILE> "Check the scan against this network exposure policy (YAML)").required(false))
+                .arg(arg!(--"check-metadata" "Check whether the cloud metadata endpoints are reachable"))
+                .arg(metadata_address_arg())
+                .arg(arg!(--details <ADDRESS> "Show the details of the device with this IP or MAC address").required(false))
+                .arg(arg!(-v --verbose "Show every port with its protocol, service, banner and vulnerabilities"))
+                .arg(
+                    Arg::new("criticality")
+                        .long("criticality")
+                        .value_name("LEVELS")
+                        .help("Only show devices with these criticalities (comma separated)")
+                        .value_delimiter(',')
+                        .action(ArgAction::Append),
+                )
+                .arg(arg!(--vendor <VENDOR> "Only show devices whose vendor contains this text").required(false))
+                .arg(
+                    Arg::new("device-type")
+                        .long("device-type")
+                        .value_name("TYPES")
+                        .help("Only show devices of these types (comma separated)")
+                        .value_delimiter(',')
+                        .action(ArgAction::Append),
+                )
+                .subcommand(
+                    Command::new("events")
+                        .about("Show the LAN events recorded by the background process")
+                        .arg(arg!(--since <SINCE> "Only show events since this RFC 3339 date or duration (e.g. 30m, 2h, 1d)").required(false))
+                        .arg(arg!(--json "Output the events as JSON")),
+                ),
+        )
+        .subcommand(
+            Command::new("sockets")
+                .about("List the listening TCP/UDP sockets of this host with their owning process (Linux)")
+                .arg(arg!(--json "Output the sockets as JSON"))
+                .arg(arg!(--policy <FILE> "Check the sockets against this policy (YAML)").required(false)),
+        )
+        .subcommand(
+            Command::new("egress")
+                .about("Outbound connections recorded by the egress monitor of the background process")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("report")
+                        .about("Summarize the outbound destinations, against an allowlist if given")
+                        .arg(arg!(--allowlist <FILE> "Allowed hosts, CIDRs and ports (YAML), fail on any other destination").required(false))
+                        .arg(arg!(--json "Output the report as JSON")),
+                ),
+        )
+        .subcommand(
+            Command::new("harden-network")
+                .about("Restrict the egress of this host to an allowlist with nftables (Linux)")
+                .arg(
+                    arg!(--allow <FILE> "Allowed hosts, CIDRs and ports (YAML, same format as the egress report allowlist)")
+                        .required_unless_present("revert")
+                        .conflicts_with("revert"),
+                )
+                .arg(arg!(--revert "Remove the egress allowlist, the rest of the ruleset is left as it is"))
+                .arg(arg!(--"dry-run" "Print the nftables ruleset without applying it").requires("allow")),
+        )
+        .subcommand(
+            Command::new("snapshot")
+                .about("Record the current state of the host, to compare it later with drift")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("processes")
+                        .about("Record the running processes, at the start of a job")
+                        .arg(arg!(--output <FILE> "Snapshot file (defaults to ~/.edamame_posture_processes.json)").required(false)),
+                ),
+        )
+        .subcommand(
+            Command::new("drift")
+                .about("Compare the host with a snapshot")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("processes")
+                        .about("Report the processes started since the snapshot, at the end of a job")
+                        .arg(arg!(--snapshot <FILE> "Snapshot file (defaults to ~/.edamame_posture_processes.json)").required(false))
+                        .arg(arg!(--json "Output the new processes as JSON"))
+                        .arg(arg!(--"fail-on-flagged" "Fail when a new process runs as root, from a temp path, from a deleted executable or detached")),
+                ),
+        )
+        .subcommand(
+            Command::new("check-memory-access")
+                .about("Detect processes reading the memory of other processes through /proc/<pid>/mem or ptrace (Linux)")
+                .arg(arg!(--json "Output the detections as JSON"))
+                .arg(arg!(--"fail-on-detection" "Fail when a process reads the memory of another process")),
+        )
+        .subcommand(
+            Command::new("fim")
+                .about("File integrity monitoring of system binaries, configuration, toolcache and git hooks")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("baseline")
+                        .about("Hash the monitored paths into a signed manifest")
+                        .arg(
+                            Arg::new("path")
+                                .long("path")
+                                .value_name("PATH")
+                                .help("Path to monitor (repeatable, defaults to /etc, /usr/bin, /usr/local/bin, ~/.cargo/bin, the runner toolcache and the git hooks)")
+                                .action(ArgAction::Append),
+                        )
+                        .arg(
+                            Arg::new("exclude")
+                                .long("exclude")
+                                .value_name("PATTERN")
+                                .help("Glob pattern of the paths to skip (repeatable)")
+                                .action(ArgAction::Append),
+                        )
+                        .arg(fim_manifest_arg())
+                        .arg(fim_key_file_arg()),
+                )
+                .subcommand(
+                    Command::new("verify")
+                        .about("Report the files added, removed or modified since the baseline")
+                        .arg(fim_manifest_arg())
+                        .arg(fim_key_file_arg())
+                        .arg(arg!(--json "Output the changes as JSON"))
+                        .arg(arg!(--"fail-on-change" "Fail when a file was added, removed, modified or became unreadable")),
+                ),
+        )
+        .subcommand(
+            Command::new("check-metadata")
+                .about("Check whether the cloud metadata endpoints (AWS IMDSv1/v2, GCP, Azure) are reachable")
+                .arg(metadata_address_arg())
+                .arg(
+                    arg!(--timeout <SECONDS> "Timeout of each probe (defaults to 2 seconds)")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(arg!(--json "Output the report as JSON"))
+                .arg(arg!(--"fail-on-exposure" "Fail when an endpoint answers without a token")),
+        )
+        .subcommand(
+            Command::new("db")
+                .about("Manage the MAC vendor and device databases used by the LAN scan")
+                .subcommand_required(true)
+                .subcommand(Command::new("status").about("Show the versions of the databases and the device overrides"))
+                .subcommand(
+                    Command::new("import-oui")
+                        .about("Import an OUI file (IEEE oui.txt or CSV, Wireshark manuf), used instead of the core database")
+                        .arg(arg!(<FILE> "OUI file").required(true)),
+                )
+                .subcommand(Command::new("remove-oui").about("Remove the imported OUI file and use the core database again")),
+        )
         .subcommand(
             Command::new("wait-for-connection")
                 .about("Wait for connection")
@@ -120,12 +469,42 @@ fn run_base() {
                     arg!(<TIMEOUT> "Timeout in seconds")
                         .required(false)
                         .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"poll-interval" <SECONDS> "Interval between two status checks (defaults to 5 seconds)")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"daemon-timeout" <SECONDS> "Timeout for the background process to be alive")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"connection-timeout" <SECONDS> "Timeout for the domain connection")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"report-timeout" <SECONDS> "Timeout for the first report to be sent")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"lanscan-timeout" <SECONDS> "Timeout for the LAN scan to complete")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
                 ),
         )
         .subcommand(Command::new("get-core-info").about("Get core information"))
         .subcommand(Command::new("get-device-info").about("Get device information"))
         .subcommand(Command::new("get-threats-info").about("Get threats information"))
-        .subcommand(Command::new("get-system-info").about("Get system information"))
+        .subcommand(
+            Command::new("get-system-info")
+                .about("Get system information")
+                .arg(arg!(--json "Output the report as JSON"))
+                .arg(arg!(--verbose "Include the raw hardware information (/proc/cpuinfo on Linux)")),
+        )
         .subcommand(
             Command::new("request-pin")
                 .about("Request PIN")
@@ -147,54 +526,373 @@ fn run_base() {
                     arg!(<LAN_SCANNING> "LAN scanning enabled")
                         .required(false)
                         .value_parser(clap::value_parser!(bool)),
-                ),
+                )
+                .arg(
+                    arg!(--"max-duration" <SECONDS> "Disconnect and stop the background process after this duration")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"parent-pid" <PID> "Disconnect and stop the background process when this process exits")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .args(network_args())
+                .args(scan_args())
+                .arg(
+                    arg!(--"lan-monitor-interval" <SECONDS> "Interval between two LAN monitoring scans, 0 to scan only once (defaults to 300 seconds)")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(arg!(--"egress-monitor" "Record the outbound connections of this host (Linux)"))
+                .arg(
+                    arg!(--"egress-interval" <SECONDS> "Interval between two samples of the outbound connections (defaults to 2 seconds)")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(arg!(--"watch-memory-access" "Watch for processes reading the memory of other processes (Linux)"))
+                .arg(arg!(--replace "Stop the running background process, if any, before starting"))
+                .arg(wait_arg()),
+        )
+        .subcommand(
+            Command::new("restart")
+                .about("Restart reporting background process with its saved parameters")
+                .arg(arg!([PIN] "PIN for authentication, not saved by start (defaults to the EDAMAME_POSTURE_PIN environment variable)").required(false))
+                .arg(
+                    arg!(--"parent-pid" <PID> "Disconnect and stop the background process when this process exits (not kept from the previous start)")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(wait_arg()),
         )
         .subcommand(Command::new("stop").about("Stop reporting background process"))
         .subcommand(Command::new("status").about("Get status of reporting background process"))
         .get_matches();
 
     match matches.subcommand() {
-        Some(("score", _)) => {
+        Some(("score", sub_matches)) => {
             // Request a score computation
             compute_score();
             handle_score(true);
+            // Local findings not covered by the threat model
+            if sub_matches.get_flag("check-metadata") {
+                let address = sub_matches.get_one::<String>("metadata-address").unwrap();
+                print_metadata_report(&check_metadata(address, 1));
+            }
+            if sub_matches.get_flag("check-memory-access") {
+                print_memory_access_threats(&detect_memory_access());
+            }
+        }
+        Some(("sockets", sub_matches)) => {
+            let sockets = match listening_sockets() {
+                Ok(sockets) => sockets,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    // Exit with an error code
+                    std::process::exit(1);
+                }
+            };
+            if sub_matches.get_flag("json") {
+                match serde_json::to_string_pretty(&sockets) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => eprintln!("Unable to serialize the sockets: {}", e),
+                }
+            } else {
+                print_listening_sockets(&sockets);
+            }
+            if let Some(path) = sub_matches.get_one::<String>("policy") {
+                let policy = match load_policy(path) {
+                    Ok(policy) => policy,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        // Exit with an error code
+                        std::process::exit(1);
+                    }
+                };
+                let violations = check_socket_policy(&policy.sockets, &sockets);
+                if !report_policy_violations("Listening socket policy", &violations) {
+                    eprintln!(
+                        "Listening socket policy violated: {} violation(s)",
+                        violations.len()
+                    );
+                    // Exit with an error code
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("egress", sub_matches)) => {
+            if let Some(("report", report_matches)) = sub_matches.subcommand() {
+                let unexpected = handle_egress_report(
+                    report_matches.get_one::<String>("allowlist"),
+                    report_matches.get_flag("json"),
+                );
+                if !unexpected.is_empty() {
+                    eprintln!(
+                        "Unexpected egress: {} destination(s) not in the allowlist",
+                        unexpected.len()
+                    );
+                    // Exit with an error code
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("harden-network", sub_matches)) => {
+            if sub_matches.get_flag("revert") {
+                handle_harden_network_revert();
+            } else {
+                handle_harden_network_allow(
+                    sub_matches.get_one::<String>("allow").unwrap(),
+                    sub_matches.get_flag("dry-run"),
+                );
+            }
+        }
+        Some(("snapshot", sub_matches)) => {
+            if let Some(("processes", processes_matches)) = sub_matches.subcommand() {
+                let path = processes_matches
+                    .get_one::<String>("output")
+                    .cloned()
+                    .unwrap_or(default_process_snapshot_path().display().to_string());
+                handle_snapshot_processes(&path);
+            }
+        }
+        Some(("drift", sub_matches)) => {
+            if let Some(("processes", processes_matches)) = sub_matches.subcommand() {
+                let path = processes_matches
+                    .get_one::<String>("snapshot")
+                    .cloned()
+                    .unwrap_or(default_process_snapshot_path().display().to_string());
+                let drift = handle_drift_processes(&path, processes_matches.get_flag("json"));
+                let flagged = drift.iter().filter(|entry| !entry.flags.is_empty()).count();
+                if processes_matches.get_flag("fail-on-flagged") && flagged > 0 {
+                    eprintln!("Process drift: {} flagged new process(es)", flagged);
+                    // Exit with an error code
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("check-memory-access", sub_matches)) => {
+            let accesses = handle_check_memory_access(sub_matches.get_flag("json"));
+            if sub_matches.get_flag("fail-on-detection") && !accesses.is_empty() {
+                eprintln!(
+                    "Active threat: {} process memory access(es) detected",
+                    accesses.len()
+                );
+                // Exit with an error code
+                std::process::exit(1);
+            }
+        }
+        Some(("fim", sub_matches)) => match sub_matches.subcommand() {
+            Some(("baseline", baseline_matches)) => {
+                let values = |id: &str| -> Vec<String> {
+                    baseline_matches
+                        .get_many::<String>(id)
+                        .map(|values| values.cloned().collect())
+                        .unwrap_or_default()
+                };
+                let mut paths = values("path");
+                if paths.is_empty() {
+                    paths = default_fim_paths();
+                }
+                handle_fim_baseline(
+                    &paths,
+                    &values("exclude"),
+                    &fim_manifest_path(baseline_matches),
+                    baseline_matches.get_one::<String>("key-file"),
+                );
+            }
+            Some(("verify", verify_matches)) => {
+                let changes = handle_fim_verify(
+                    &fim_manifest_path(verify_matches),
+                    verify_matches.get_one::<String>("key-file"),
+                    verify_matches.get_flag("json"),
+                );
+                if verify_matches.get_flag("fail-on-change") && !changes.is_empty() {
+                    eprintln!(
+                        "File integrity: {} change(s) since the baseline",
+                        changes.len()
+                    );
+                    // Exit with an error code
+                    std::process::exit(1);
+                }
+            }
+            _ => {}
+        },
+        Some(("check-metadata", sub_matches)) => {
+            let address = sub_matches.get_one::<String>("metadata-address").unwrap();
+            let timeout = sub_matches.get_one::<u64>("timeout").copied().unwrap_or(2);
+            let report = handle_check_metadata(address, timeout, sub_matches.get_flag("json"));
+            if sub_matches.get_flag("fail-on-exposure") && report.is_exposed() {
+                eprintln!("Cloud metadata endpoint reachable without a token");
+                // Exit with an error code
+                std::process::exit(1);
+            }
+        }
+        Some(("lanscan", sub_matches)) if sub_matches.subcommand_matches("events").is_some() => {
+            let events_matches = sub_matches.subcommand_matches("events").unwrap();
+            handle_lan_events(
+                events_matches.get_one::<String>("since"),
+                events_matches.get_flag("json"),
+            );
         }
-        Some(("lanscan", _)) => {
-            // Initialize network
-            set_network(LANScanAPINetwork {
-                interfaces: vec![],
-                scanned_interfaces: vec![],
-                is_ethernet: true,
-                is_wifi: false,
-                is_vpn: false,
-                is_tethering: false,
-                is_mobile: false,
-                wifi_bssid: "".to_string(),
-                wifi_ip: "".to_string(),
-                wifi_submask: "".to_string(),
-                wifi_gateway: "".to_string(),
-                wifi_broadcast: "".to_string(),
-                wifi_name: "".to_string(),
-                wifi_ipv6: "".to_string(),
-            });
-
-            // Grant consent
-            grant_consent();
-
-            // Wait for the gateway detection to complete
-            let mut last_gateway_scan = get_last_gateway_scan();
-            while last_gateway_scan == "" {
-                println!("Waiting for gateway detection to complete...");
-                sleep(Duration::from_secs(20));
-                last_gateway_scan = get_last_gateway_scan();
+        Some(("lanscan", sub_matches)) => {
+            // Initialize network and wait for the gateway
+            let selection = network_selection(sub_matches);
+            let gateway = gateway_options(sub_matches);
+            let scan = scan_options(sub_matches);
+            if gateway.gateway.is_none() {
+                println!(
+                    "Waiting for gateway detection to complete (timeout: {}s)...",
+                    gateway.timeout
+                );
+            }
+            match prepare_lan_scan(&selection, &gateway) {
+                Ok(outcome) => println!("{}", outcome),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    // Exit with an error code
+                    std::process::exit(1);
+                }
+            }
+
+            let display = LanDisplayOptions {
+                verbose: sub_matches.get_flag("verbose"),
+                details: sub_matches.get_one::<String>("details").cloned(),
+                criticality: sub_matches
+                    .get_many::<String>("criticality")
+                    .map(|values| values.cloned().collect())
+                    .unwrap_or_default(),
+                vendor: sub_matches.get_one::<String>("vendor").cloned(),
+                device_type: sub_matches
+                    .get_many::<String>("device-type")
+                    .map(|values| values.cloned().collect())
+                    .unwrap_or_default(),
+            };
+            let mut inventory = match run_lan_scan(&selection, &scan, &display) {
+                Ok(inventory) => inventory,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    // Exit with an error code
+                    std::process::exit(1);
+                }
+            };
+            if inventory.gateway.is_empty() {
+                inventory.gateway = gateway.gateway.clone().unwrap_or_default();
+            }
+
+            if let Some(address) = &display.details {
+                if !inventory
+                    .devices
+                    .iter()
+                    .any(|device| display.matches(device))
+                {
+                    eprintln!("Device {} not found", address);
+                    // Exit with an error code
+                    std::process::exit(1);
+                }
+            }
+
+            // Export the inventory if requested
+            if let Some(output) = sub_matches.get_one::<String>("output") {
+                let format = sub_matches
+                    .get_one::<String>("format")
+                    .and_then(|format| ExportFormat::parse(format))
+                    .unwrap_or(ExportFormat::Json);
+                match export_lan_inventory(&inventory, format, output) {
+                    Ok(_) => println!("LAN inventory exported to {}", output),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        // Exit with an error code
+                        std::process::exit(1);
+                    }
+                }
             }
-            println!("Gateway detection complete");
 
-            // Request a LAN scan
-            _ = get_lan_devices(true, false, false);
+            // Output the topology if requested
+            if let Some(format) = sub_matches
+                .get_one::<String>("graph")
+                .and_then(|format| GraphFormat::parse(format))
+            {
+                let path = sub_matches.get_one::<String>("graph-output");
+                match export_lan_topology(&inventory, format, path) {
+                    Ok(_) => {
+                        if let Some(path) = path {
+                            println!("LAN topology written to {}", path);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        // Exit with an error code
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            let mut failed = false;
+
+            // Compare with the baseline if requested
+            if let Some(baseline) = sub_matches.get_one::<String>("baseline") {
+                let fail_on = sub_matches
+                    .get_many::<String>("fail-on")
+                    .map(|values| values.cloned().collect::<Vec<String>>())
+                    .unwrap_or_default();
+                let update = sub_matches.get_flag("update-baseline");
+                let failures = handle_lan_baseline(&inventory, baseline, update, &fail_on);
+                if !failures.is_empty() {
+                    eprintln!(
+                        "LAN drift detected: {} change(s) matching --fail-on",
+                        failures.len()
+                    );
+                    failed = true;
+                }
+            }
+
+            // Check the cloud metadata endpoints if requested
+            let metadata_address = sub_matches.get_one::<String>("metadata-address").unwrap();
+            let mut metadata = None;
+            if sub_matches.get_flag("check-metadata") {
+                let report = check_metadata(metadata_address, 2);
+                print_metadata_report(&report);
+                metadata = Some(report);
+            }
+
+            // Check the network exposure policy if requested
+            if let Some(path) = sub_matches.get_one::<String>("policy") {
+                let policy = match load_policy(path) {
+                    Ok(policy) => policy,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        // Exit with an error code
+                        std::process::exit(1);
+                    }
+                };
+                let mut violations = check_lan_policy(&policy.lan, &inventory);
+                if policy.metadata.is_enabled() {
+                    let report =
+                        metadata.get_or_insert_with(|| check_metadata(metadata_address, 2));
+                    violations.extend(check_metadata_policy(&policy.metadata, report));
+                }
+                if !report_policy_violations("Network exposure policy", &violations) {
+                    eprintln!(
+                        "Network exposure policy violated: {} violation(s)",
+                        violations.len()
+                    );
+                    failed = true;
+                }
+            }
 
-            handle_lanscan(true);
+            if failed {
+                // Exit with an error code
+                std::process::exit(1);
+            }
         }
+        Some(("db", sub_matches)) => match sub_matches.subcommand() {
+            Some(("status", _)) => handle_db_status(),
+            Some(("import-oui", import_matches)) => {
+                handle_db_import_oui(import_matches.get_one::<String>("FILE").unwrap())
+            }
+            Some(("remove-oui", _)) => handle_db_remove_oui(),
+            _ => eprintln!("Invalid command, use --help for more information"),
+        },
         Some(("wait-for-connection", sub_matches)) => {
             let timeout = match sub_matches.get_one::<u64>("TIMEOUT") {
                 Some(timeout) => timeout,
@@ -203,12 +901,23 @@ fn run_base() {
                     &600
                 }
             };
-            handle_wait_for_connection(*timeout);
+            let mut options = WaitOptions::new(*timeout);
+            if let Some(poll_interval) = sub_matches.get_one::<u64>("poll-interval") {
+                options.poll_interval = *poll_interval;
+            }
+            options.daemon_timeout = sub_matches.get_one::<u64>("daemon-timeout").copied();
+            options.connection_timeout = sub_matches.get_one::<u64>("connection-timeout").copied();
+            options.report_timeout = sub_matches.get_one::<u64>("report-timeout").copied();
+            options.lanscan_timeout = sub_matches.get_one::<u64>("lanscan-timeout").copied();
+            handle_wait_for_connection(&options);
         }
         Some(("get-core-info", _)) => handle_get_core_info(),
         Some(("get-device-info", _)) => handle_get_device_info(),
         Some(("get-threats-info", _)) => handle_get_threats_info(),
-        Some(("get-system-info", _)) => handle_get_system_info(),
+        Some(("get-system-info", sub_matches)) => handle_get_system_info(
+            sub_matches.get_flag("verbose"),
+            sub_matches.get_flag("json"),
+        ),
         Some(("request-pin", sub_matches)) => {
             let user = sub_matches.get_one::<String>("USER").unwrap().to_string();
             let domain = sub_matches.get_one::<String>("DOMAIN").unwrap().to_string();
@@ -244,10 +953,52 @@ fn run_base() {
             let lan_scanning = sub_matches
                 .get_one::<bool>("LAN_SCANNING")
                 .unwrap_or(&false);
-            start_background_process(user, domain, pin, device_id, *lan_scanning);
+            // 0 means no limit
+            let max_duration = sub_matches
+                .get_one::<u64>("max-duration")
+                .copied()
+                .unwrap_or(0);
+            let parent_pid = sub_matches.get_one::<u32>("parent-pid").copied();
+            let replace = sub_matches.get_flag("replace");
+            let wait = sub_matches.get_one::<u64>("wait").copied();
+            let params = BackgroundParams {
+                user,
+                domain,
+                pin,
+                device_id,
+                lan_scanning: *lan_scanning,
+                max_duration,
+                parent_pid,
+                network: network_selection(sub_matches),
+                gateway: gateway_options(sub_matches),
+                lan_monitor_interval: sub_matches
+                    .get_one::<u64>("lan-monitor-interval")
+                    .copied()
+                    .unwrap_or(DEFAULT_LAN_MONITOR_INTERVAL),
+                scan: scan_options(sub_matches),
+                egress_interval: if sub_matches.get_flag("egress-monitor") {
+                    sub_matches
+                        .get_one::<u64>("egress-interval")
+                        .copied()
+                        .unwrap_or(2)
+                        .max(1)
+                } else {
+                    0
+                },
+                memory_watch: sub_matches.get_flag("watch-memory-access"),
+            };
+            start_background_process(params, replace, wait);
+        }
+        Some(("restart", sub_matches)) => {
+            let parent_pid = sub_matches.get_one::<u32>("parent-pid").copied();
+            let wait = sub_matches.get_one::<u64>("wait").copied();
+            restart_background_process(sub_matches.get_one::<String>("PIN"), parent_pid, wait);
         }
         Some(("stop", _)) => stop_background_process(),
-        Some(("status", _)) => show_background_process_status(),
+        Some(("status", _)) => {
+            show_background_process_status();
+            show_harden_status();
+        }
         _ => eprintln!("Invalid command, use --help for more information"),
     }
 }
@@ -0,0 +1,294 @@
+use crate::process_identity;
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::{error, warn};
+
+// Process accessing the memory of another process
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MemoryAccess {
+    // mem-reader (holds /proc/<pid>/mem open) or ptrace (TracerPid)
+    pub kind: String,
+    pub accessor_pid: u32,
+    pub accessor_name: String,
+    pub accessor_exe: String,
+    pub target_pid: u32,
+    pub target_name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MemoryAccessEvent {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub access: MemoryAccess,
+}
+
+fn memory_access_file_path() -> PathBuf {
+    dirs::home_dir()
+        .expect("Unable to find home directory")
+        .join(".edamame_posture_memory_access.jsonl")
+}
+
+fn pids() -> Vec<u32> {
+    match fs::read_dir("/proc") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_string_lossy().parse::<u32>().ok())
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+// Numeric field of a /proc status file, e.g. "TracerPid:\t1234"
+fn parse_status_field(status: &str, key: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(key))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn status_field(path: &str, key: &str) -> Option<u32> {
+    parse_status_field(&fs::read_to_string(path).ok()?, key)
+}
+
+// Process of a thread, /proc/<tid> exists for every thread
+fn tgid(pid: u32) -> u32 {
+    status_field(&format!("/proc/{}/status", pid), "Tgid:").unwrap_or(pid)
+}
+
+// Tracers of any thread of the process, a debugger can attach to a single thread
+fn tracer_pids(pid: u32) -> Vec<u32> {
+    let tasks = match fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(tasks) => tasks,
+        Err(_) => return vec![],
+    };
+    let mut tracers: Vec<u32> = tasks
+        .filter_map(|task| task.ok())
+        .filter_map(|task| {
+            status_field(
+                &format!("{}/status", task.path().to_string_lossy()),
+                "TracerPid:",
+            )
+        })
+        .filter(|tracer| *tracer != 0)
+        // The tracer is a thread, report its process
+        .map(tgid)
+        .collect();
+    tracers.sort();
+    tracers.dedup();
+    tracers
+}
+
+// PID or TID of a "/proc/<pid>/mem" or "/proc/<pid>/task/<tid>/mem" file descriptor link
+fn parse_mem_link(link: &str) -> Option<u32> {
+    let rest = link.strip_prefix("/proc/")?.strip_suffix("/mem")?;
+    match rest.split_once("/task/") {
+        Some((_, tid)) => tid.parse().ok(),
+        None => rest.parse().ok(),
+    }
+}
+
+// Target processes of the /proc/<pid>/mem files held open by the process
+fn open_mem_files(pid: u32) -> Vec<u32> {
+    let fds = match fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(fds) => fds,
+        Err(_) => return vec![],
+    };
+    let mut targets: Vec<u32> = fds
+        .filter_map(|fd| fd.ok())
+        .filter_map(|fd| fs::read_link(fd.path()).ok())
+        .filter_map(|link| parse_mem_link(&link.to_string_lossy()))
+        // The file may be opened through a thread, report its process
+        .map(tgid)
+        .collect();
+    targets.sort();
+    targets.dedup();
+    targets
+}
+
+fn memory_access(kind: &str, accessor_pid: u32, target_pid: u32) -> MemoryAccess {
+    let (accessor_name, accessor_exe) = process_identity(accessor_pid);
+    let (target_name, _) = process_identity(target_pid);
+    MemoryAccess {
+        kind: kind.to_string(),
+        accessor_pid,
+        accessor_name,
+        accessor_exe,
+        target_pid,
+        target_name,
+    }
+}
+
+// The file descriptors of other users' processes are only visible to root
+pub fn detect_memory_access() -> Vec<MemoryAccess> {
+    if !cfg!(target_os = "linux") {
+        return vec![];
+    }
+    let own_pid = std::process::id();
+    let mut accesses = Vec::new();
+    for pid in pids() {
+        if pid == own_pid {
+            continue;
+        }
+        for target in open_mem_files(pid) {
+            // Reading its own memory is harmless
+            if target != pid {
+                accesses.push(memory_access("mem-reader", pid, target));
+            }
+        }
+        for tracer in tracer_pids(pid) {
+            if tracer != own_pid {
+                accesses.push(memory_access("ptrace", tracer, pid));
+            }
+        }
+    }
+    accesses
+}
+
+pub fn describe_memory_access(access: &MemoryAccess) -> String {
+    let action = if access.kind == "ptrace" {
+        "is ptrace-attached to".to_string()
+    } else {
+        "reads, through /proc/<pid>/mem, the memory of".to_string()
+    };
+    format!(
+        "{} (pid {}, {}) {} {} (pid {})",
+        access.accessor_name,
+        access.accessor_pid,
+        access.accessor_exe,
+        action,
+        access.target_name,
+        access.target_pid
+    )
+}
+
+// Active threat section of the posture output
+pub fn print_memory_access_threats(accesses: &[MemoryAccess]) {
+    println!("Process memory access:");
+    if accesses.is_empty() {
+        println!("  - No process reading the memory of another process");
+    }
+    for access in accesses.iter() {
+        println!("  - Active threat: {}", describe_memory_access(access));
+    }
+    println!();
+}
+
+// Append new detections of the background process watch, one JSON object per line
+pub fn record_memory_access(accesses: &[MemoryAccess]) {
+    if accesses.is_empty() {
+        return;
+    }
+    let timestamp = Utc::now();
+    let mut lines = String::new();
+    for access in accesses.iter() {
+        warn!("Process memory access: {}", describe_memory_access(access));
+        let event = MemoryAccessEvent {
+            timestamp,
+            access: access.clone(),
+        };
+        match serde_json::to_string(&event) {
+            Ok(line) => {
+                lines.push_str(&line);
+                lines.push('\n');
+            }
+            Err(e) => error!("Unable to serialize memory access event: {}", e),
+        }
+    }
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(memory_access_file_path())
+        .and_then(|mut file| {
+            file.lock_exclusive()?;
+            file.write_all(lines.as_bytes())?;
+            file.unlock()
+        });
+    if let Err(e) = result {
+        error!("Unable to write memory access events: {}", e);
+    }
+}
+
+pub fn read_memory_access_events() -> Vec<MemoryAccessEvent> {
+    let contents = fs::read_to_string(memory_access_file_path()).unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|line| serde_json::from_str::<MemoryAccessEvent>(line).ok())
+        .collect()
+}
+
+// Each start of the watch begins a new record
+pub fn clear_memory_access_events() {
+    let path = memory_access_file_path();
+    if path.exists() {
+        if let Err(e) = fs::remove_file(path) {
+            error!("Unable to clear memory access events: {}", e);
+        }
+    }
+}
+
+pub fn handle_check_memory_access(json: bool) -> Vec<MemoryAccess> {
+    let accesses = detect_memory_access();
+    if json {
+        match serde_json::to_string_pretty(&accesses) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Unable to serialize memory accesses: {}", e),
+        }
+    } else {
+        print_memory_access_threats(&accesses);
+    }
+    accesses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_fields() {
+        let status = "Name:\tcat\nUmask:\t0022\nState:\tR (running)\nTgid:\t1234\nNgid:\t0\nPid:\t1240\nPPid:\t1000\nTracerPid:\t0\n";
+        assert_eq!(parse_status_field(status, "Tgid:"), Some(1234));
+        assert_eq!(parse_status_field(status, "Pid:"), Some(1240));
+        assert_eq!(parse_status_field(status, "TracerPid:"), Some(0));
+        assert_eq!(parse_status_field(status, "Name:"), None);
+        assert_eq!(parse_status_field(status, "Seccomp:"), None);
+    }
+
+    #[test]
+    fn mem_links() {
+        let cases = [
+            ("/proc/1234/mem", Some(1234)),
+            ("/proc/1234/task/1240/mem", Some(1240)),
+            ("/proc/self/mem", None),
+            ("/proc/1234/maps", None),
+            ("/tmp/proc/1234/mem", None),
+            ("/proc/1234/mem (deleted)", None),
+            ("socket:[12345]", None),
+        ];
+        for (link, pid) in cases {
+            assert_eq!(parse_mem_link(link), pid, "{}", link);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn thread_process() {
+        // A thread resolves to its process, kept alive until the check is done
+        let (tid_sender, tid_receiver) = std::sync::mpsc::channel();
+        let (done_sender, done_receiver) = std::sync::mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let link = fs::read_link("/proc/thread-self").unwrap();
+            let tid: u32 = link.file_name().unwrap().to_string_lossy().parse().unwrap();
+            tid_sender.send(tid).unwrap();
+            done_receiver.recv().ok();
+        });
+        let tid = tid_receiver.recv().unwrap();
+        assert_ne!(tid, std::process::id());
+        assert_eq!(tgid(tid), std::process::id());
+        done_sender.send(()).unwrap();
+        thread.join().unwrap();
+    }
+}
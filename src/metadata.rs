@@ -0,0 +1,365 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// Link-local address of the AWS, GCP and Azure metadata services
+pub const DEFAULT_METADATA_ADDRESS: &str = "169.254.169.254";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MetadataEndpoint {
+    // aws-imdsv1, aws-imdsv2, gcp, gcp-v1beta1 or azure
+    pub endpoint: String,
+    pub reachable: bool,
+    // Answers without a session token or a metadata request header
+    pub unauthenticated: bool,
+    pub details: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MetadataReport {
+    pub address: String,
+    pub endpoints: Vec<MetadataEndpoint>,
+}
+
+impl MetadataReport {
+    pub fn is_reachable(&self) -> bool {
+        self.endpoints.iter().any(|endpoint| endpoint.reachable)
+    }
+
+    // Credentials can be read by any process or SSRF without a token
+    pub fn is_exposed(&self) -> bool {
+        self.endpoints
+            .iter()
+            .any(|endpoint| endpoint.reachable && endpoint.unauthenticated)
+    }
+}
+
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl HttpResponse {
+    fn header(&self, name: &str) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+}
+
+// Minimal HTTP/1.0 client, metadata requests must never go through a proxy
+fn http_request(
+    address: &SocketAddr,
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    timeout: Duration,
+) -> Result<HttpResponse, String> {
+    let mut stream = TcpStream::connect_timeout(address, timeout).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .map_err(|e| e.to_string())?;
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, host);
+    for (name, value) in headers.iter() {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if method == "PUT" {
+        request.push_str("Content-Length: 0\r\n");
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    // The metadata documents are small, do not read more than 64 KiB
+    let mut response = Vec::new();
+    stream
+        .take(65536)
+        .read_to_end(&mut response)
+        .map_err(|e| e.to_string())?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or("Invalid HTTP response".to_string())?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok(HttpResponse {
+        status,
+        headers,
+        body: body.to_string(),
+    })
+}
+
+fn endpoint(
+    name: &str,
+    reachable: bool,
+    unauthenticated: bool,
+    details: String,
+) -> MetadataEndpoint {
+    MetadataEndpoint {
+        endpoint: name.to_string(),
+        reachable,
+        unauthenticated,
+        details,
+    }
+}
+
+fn describe(result: &Result<HttpResponse, String>) -> String {
+    match result {
+        Ok(response) => format!("HTTP {}", response.status),
+        Err(e) => e.clone(),
+    }
+}
+
+// Probe the metadata endpoints at address (host or host:port)
+pub fn check_metadata(address: &str, timeout: u64) -> MetadataReport {
+    let timeout = Duration::from_secs(timeout.max(1));
+    let mut report = MetadataReport {
+        address: address.to_string(),
+        endpoints: vec![],
+    };
+    // host, host:port, IPv6 address or [IPv6]:port
+    let with_port = match address.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 80).to_string(),
+        Err(_) if address.contains(':') => address.to_string(),
+        Err(_) => format!("{}:80", address),
+    };
+    let socket = match with_port
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+    {
+        Some(socket) => socket,
+        None => {
+            report.endpoints.push(endpoint(
+                "all",
+                false,
+                false,
+                format!("invalid address {}", address),
+            ));
+            return report;
+        }
+    };
+    let request = |method: &str, path: &str, headers: &[(&str, &str)]| {
+        http_request(&socket, address, method, path, headers, timeout)
+    };
+
+    // Nothing listening, skip the other probes
+    if let Err(e) = TcpStream::connect_timeout(&socket, timeout) {
+        report
+            .endpoints
+            .push(endpoint("all", false, false, e.to_string()));
+        return report;
+    }
+
+    // AWS IMDSv1, no session token
+    let v1 = request("GET", "/latest/meta-data/", &[]);
+    let v1_open = matches!(&v1, Ok(response) if response.status == 200);
+    report
+        .endpoints
+        .push(endpoint("aws-imdsv1", v1_open, true, describe(&v1)));
+
+    // AWS IMDSv2, session token obtained with a PUT
+    let token = request(
+        "PUT",
+        "/latest/api/token",
+        &[("X-aws-ec2-metadata-token-ttl-seconds", "60")],
+    );
+    let v2 = match &token {
+        Ok(response) if response.status == 200 && !response.body.trim().is_empty() => {
+            let token = response.body.trim().to_string();
+            request(
+                "GET",
+                "/latest/meta-data/",
+                &[("X-aws-ec2-metadata-token", &token)],
+            )
+        }
+        _ => token,
+    };
+    report.endpoints.push(endpoint(
+        "aws-imdsv2",
+        matches!(&v2, Ok(response) if response.status == 200),
+        false,
+        describe(&v2),
+    ));
+
+    // GCP requires the Metadata-Flavor header, the legacy v1beta1 API did not
+    let gcp = request(
+        "GET",
+        "/computeMetadata/v1/",
+        &[("Metadata-Flavor", "Google")],
+    );
+    let gcp_open = matches!(&gcp, Ok(response) if response.status == 200
+        && response.header("Metadata-Flavor").map(|v| v == "Google").unwrap_or(false));
+    report
+        .endpoints
+        .push(endpoint("gcp", gcp_open, false, describe(&gcp)));
+    let beta = request("GET", "/computeMetadata/v1beta1/", &[]);
+    let beta_open = matches!(&beta, Ok(response) if response.status == 200
+        && response.header("Metadata-Flavor").map(|v| v == "Google").unwrap_or(false));
+    report
+        .endpoints
+        .push(endpoint("gcp-v1beta1", beta_open, true, describe(&beta)));
+
+    // Azure requires the Metadata header
+    let azure = request(
+        "GET",
+        "/metadata/instance?api-version=2021-02-01",
+        &[("Metadata", "true")],
+    );
+    report.endpoints.push(endpoint(
+        "azure",
+        matches!(&azure, Ok(response) if response.status == 200),
+        false,
+        describe(&azure),
+    ));
+
+    report
+}
+
+pub fn print_metadata_report(report: &MetadataReport) {
+    println!("Cloud metadata endpoints ({}):", report.address);
+    for endpoint in report.endpoints.iter() {
+        println!(
+            "  - {}: {}{} ({})",
+            endpoint.endpoint,
+            if endpoint.reachable {
+                "reachable"
+            } else {
+                "not reachable"
+            },
+            if endpoint.reachable && endpoint.unauthenticated {
+                " without authentication"
+            } else {
+                ""
+            },
+            endpoint.details
+        );
+    }
+    if report.is_exposed() {
+        println!("  - Finding: metadata credentials can be read without a token, enforce IMDSv2 (HttpTokens=required) or block the endpoint");
+    } else if report.is_reachable() {
+        println!("  - Finding: metadata endpoint reachable with a token or header, block it if the workload does not need it");
+    } else {
+        println!("  - No metadata endpoint reachable");
+    }
+    println!();
+}
+
+pub fn handle_check_metadata(address: &str, timeout: u64, json: bool) -> MetadataReport {
+    let report = check_metadata(address, timeout);
+    if json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Unable to serialize the metadata report: {}", e),
+        }
+    } else {
+        print_metadata_report(&report);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    // Stand-in for the metadata service, answering the AWS paths only
+    fn serve(imdsv1: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut lines = Vec::new();
+                let mut reader = BufReader::new(&stream);
+                loop {
+                    let mut line = String::new();
+                    match reader.read_line(&mut line) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) if line.trim().is_empty() => break,
+                        Ok(_) => lines.push(line.trim().to_string()),
+                    }
+                }
+                // The reachability probe sends nothing
+                let request = match lines.first() {
+                    Some(request) => request.clone(),
+                    None => continue,
+                };
+                let token = lines
+                    .iter()
+                    .any(|line| line == "X-aws-ec2-metadata-token: secret");
+                let (status, body) = match request.as_str() {
+                    "PUT /latest/api/token HTTP/1.0" => ("200 OK", "secret"),
+                    "GET /latest/meta-data/ HTTP/1.0" if imdsv1 || token => ("200 OK", "ami-id"),
+                    "GET /latest/meta-data/ HTTP/1.0" => ("401 Unauthorized", ""),
+                    _ => ("404 Not Found", ""),
+                };
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.0 {}\r\nContent-Length: {}\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                );
+            }
+        });
+        address
+    }
+
+    fn reachable(report: &MetadataReport, name: &str) -> bool {
+        report
+            .endpoints
+            .iter()
+            .find(|endpoint| endpoint.endpoint == name)
+            .map(|endpoint| endpoint.reachable)
+            .unwrap()
+    }
+
+    #[test]
+    fn imdsv1_open() {
+        let report = check_metadata(&serve(true), 1);
+        assert!(reachable(&report, "aws-imdsv1"));
+        assert!(reachable(&report, "aws-imdsv2"));
+        assert!(!reachable(&report, "gcp"));
+        assert!(!reachable(&report, "azure"));
+        assert!(report.is_exposed());
+    }
+
+    #[test]
+    fn imdsv2_only() {
+        let report = check_metadata(&serve(false), 1);
+        assert!(!reachable(&report, "aws-imdsv1"));
+        assert!(reachable(&report, "aws-imdsv2"));
+        assert!(report.is_reachable());
+        assert!(!report.is_exposed());
+    }
+
+    #[test]
+    fn not_listening() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let report = check_metadata(&address, 1);
+        assert_eq!(report.endpoints.len(), 1);
+        assert_eq!(report.endpoints[0].endpoint, "all");
+        assert!(!report.is_reachable());
+    }
+}
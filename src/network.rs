@@ -0,0 +1,496 @@
+use crate::detect_link;
+use edamame_core::api::api_lanscan::{
+    get_last_gateway_scan, grant_consent, set_network, LANScanAPINetwork,
+};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Interfaces and subnets to scan, autodetected by the core when empty
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NetworkSelection {
+    pub interfaces: Vec<String>,
+    pub cidrs: Vec<String>,
+    pub exclude_cidrs: Vec<String>,
+    // IPv6 neighbor discovery on the selected interfaces
+    #[serde(default)]
+    pub ipv6: bool,
+}
+
+// Local IPv4 interface in the (ip, prefix, name) form used by the core
+type ScanInterface = (String, u32, String);
+
+// IPv4 and IPv6 subnets of a list of CIDRs
+fn parse_cidrs(cidrs: &[String]) -> Result<(Vec<Ipv4Net>, Vec<Ipv6Net>), String> {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for cidr in cidrs.iter() {
+        match cidr.parse::<IpNet>() {
+            Ok(IpNet::V4(net)) => v4.push(net),
+            Ok(IpNet::V6(net)) => v6.push(net),
+            Err(e) => return Err(format!("Invalid CIDR {}: {}", cidr, e)),
+        }
+    }
+    Ok((v4, v6))
+}
+
+fn local_interfaces() -> Result<Vec<(Ipv4Addr, u8, String)>, String> {
+    let interfaces =
+        if_addrs::get_if_addrs().map_err(|e| format!("Unable to list interfaces: {}", e))?;
+    Ok(interfaces
+        .into_iter()
+        .filter(|interface| !interface.is_loopback())
+        .filter_map(|interface| match interface.addr {
+            if_addrs::IfAddr::V4(addr) => {
+                let prefix = u32::from(addr.netmask).count_ones() as u8;
+                Some((addr.ip, prefix, interface.name))
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+fn local_interfaces6() -> Result<Vec<(Ipv6Addr, u8, String)>, String> {
+    let interfaces =
+        if_addrs::get_if_addrs().map_err(|e| format!("Unable to list interfaces: {}", e))?;
+    Ok(interfaces
+        .into_iter()
+        .filter(|interface| !interface.is_loopback())
+        .filter_map(|interface| match interface.addr {
+            if_addrs::IfAddr::V6(addr) => {
+                let prefix = u128::from(addr.netmask).count_ones() as u8;
+                Some((addr.ip, prefix, interface.name))
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+pub fn is_link_local6(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+// Split a subnet around an excluded range, CIDRs are either nested or disjoint
+fn subtract_cidr(net: Ipv4Net, exclude: &Ipv4Net) -> Vec<Ipv4Net> {
+    if exclude.contains(&net) {
+        return vec![];
+    }
+    if !net.contains(exclude) {
+        return vec![net];
+    }
+    match net.subnets(net.prefix_len() + 1) {
+        Ok(halves) => halves
+            .flat_map(|half| subtract_cidr(half, exclude))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+// Subnets left to scan once the excluded ranges are carved out
+pub fn carve_cidrs(net: Ipv4Net, exclude_cidrs: &[Ipv4Net]) -> Vec<Ipv4Net> {
+    let mut pieces = vec![net.trunc()];
+    for exclude in exclude_cidrs.iter() {
+        pieces = pieces
+            .into_iter()
+            .flat_map(|piece| subtract_cidr(piece, exclude))
+            .collect();
+    }
+    pieces
+}
+
+impl NetworkSelection {
+    pub fn is_empty(&self) -> bool {
+        self.interfaces.is_empty() && self.cidrs.is_empty() && self.exclude_cidrs.is_empty()
+    }
+
+    // Only excludes were given, the interface is still autodetected
+    pub fn is_autodetected(&self) -> bool {
+        self.interfaces.is_empty() && self.cidrs.is_empty()
+    }
+
+    // Resolve the selection against the local interfaces
+    pub fn scan_interfaces(&self) -> Result<Vec<ScanInterface>, String> {
+        let (cidrs, cidrs6) = parse_cidrs(&self.cidrs)?;
+        // Without --ipv6 an IPv6 CIDR would be ignored, and the IPv4 interfaces scanned instead
+        if let Some(cidr) = cidrs6.first().filter(|_| !self.ipv6) {
+            return Err(format!(
+                "CIDR {} is an IPv6 subnet, scan it with --ipv6",
+                cidr
+            ));
+        }
+        let (exclude_cidrs, _) = parse_cidrs(&self.exclude_cidrs)?;
+        let mut candidates = local_interfaces()?;
+
+        if !self.interfaces.is_empty() {
+            for name in self.interfaces.iter() {
+                if !candidates.iter().any(|(_, _, n)| n == name) {
+                    return Err(format!(
+                        "Interface {} not found or has no IPv4 address",
+                        name
+                    ));
+                }
+            }
+            candidates.retain(|(_, _, name)| self.interfaces.contains(name));
+        } else if self.is_autodetected() {
+            // The interface of the default route, as the core autodetection would pick
+            let link = detect_link(None);
+            candidates.retain(|(_, _, name)| *name == link.interface);
+            if candidates.is_empty() {
+                return Err(
+                    "Unable to autodetect the interface to scan, select one with --interface or --cidr"
+                        .to_string(),
+                );
+            }
+        }
+
+        // IPv6 subnets only, scan the IPv4 subnets of their interfaces
+        if cidrs.is_empty() && !cidrs6.is_empty() {
+            let names: Vec<String> = local_interfaces6()?
+                .into_iter()
+                .filter(|(ip, _, _)| cidrs6.iter().any(|cidr| cidr.contains(ip)))
+                .map(|(_, _, name)| name)
+                .collect();
+            candidates.retain(|(_, _, name)| names.contains(name));
+        }
+
+        // Subnets with the interface they are scanned through
+        let mut subnets: Vec<(Ipv4Addr, Ipv4Net, String)> = Vec::new();
+        // Every address of an interface is a subnet of its own
+        if cidrs.is_empty() {
+            for (ip, prefix, name) in candidates.iter() {
+                if let Ok(net) = Ipv4Net::new(*ip, *prefix) {
+                    subnets.push((*ip, net, name.clone()));
+                }
+            }
+        } else {
+            // Scan each subnet through the local interface that sits in it
+            for cidr in cidrs.iter() {
+                match candidates.iter().find(|(ip, _, _)| cidr.contains(ip)) {
+                    Some((ip, _, name)) => subnets.push((*ip, *cidr, name.clone())),
+                    None => {
+                        return Err(format!(
+                            "CIDR {} is not reachable from a selected local interface",
+                            cidr
+                        ))
+                    }
+                }
+            }
+        }
+
+        // The core scans the subnet of each (address, prefix), the pieces without our address are given by their network address
+        let mut selected: Vec<ScanInterface> = Vec::new();
+        for (ip, net, name) in subnets.iter() {
+            for piece in carve_cidrs(*net, &exclude_cidrs) {
+                let address = if piece.contains(ip) {
+                    *ip
+                } else {
+                    piece.network()
+                };
+                selected.push((address.to_string(), piece.prefix_len() as u32, name.clone()));
+            }
+        }
+
+        if selected.is_empty() {
+            return Err("No interface left to scan after applying the selection".to_string());
+        }
+        Ok(selected)
+    }
+
+    // IPv6 subnets of the selection, each address of an interface being a subnet of its own
+    pub fn scan_interfaces6(&self) -> Result<Vec<(Ipv6Addr, u8, String)>, String> {
+        let (_, cidrs) = parse_cidrs(&self.cidrs)?;
+        let (_, exclude_cidrs) = parse_cidrs(&self.exclude_cidrs)?;
+        let mut candidates = local_interfaces6()?;
+        if !self.interfaces.is_empty() {
+            candidates.retain(|(_, _, name)| self.interfaces.contains(name));
+        }
+
+        let mut selected = Vec::new();
+        if cidrs.is_empty() {
+            selected = candidates;
+        } else {
+            for cidr in cidrs.iter() {
+                match candidates.iter().find(|(ip, _, _)| cidr.contains(ip)) {
+                    Some((ip, _, name)) => selected.push((*ip, cidr.prefix_len(), name.clone())),
+                    None => {
+                        return Err(format!(
+                            "CIDR {} is not reachable from a selected local interface",
+                            cidr
+                        ))
+                    }
+                }
+            }
+        }
+        selected.retain(|(ip, _, _)| !exclude_cidrs.iter().any(|cidr| cidr.contains(ip)));
+        Ok(selected)
+    }
+
+    pub fn to_network(&self) -> Result<LANScanAPINetwork, String> {
+        // Let the core autodetect if nothing was selected
+        let mut network = if self.is_empty() {
+            Self::autodetect()
+        } else {
+            let interfaces = self.scan_interfaces()?;
+            let link = detect_link(interfaces.first().map(|(_, _, name)| name.as_str()));
+            LANScanAPINetwork {
+                interfaces: interfaces.clone(),
+                scanned_interfaces: interfaces,
+                ..link.to_network()
+            }
+        };
+        // Global IPv6 address of the selected interfaces
+        if self.ipv6 {
+            network.wifi_ipv6 = self
+                .scan_interfaces6()?
+                .iter()
+                .map(|(ip, _, _)| ip)
+                .find(|ip| !is_link_local6(ip))
+                .map(|ip| ip.to_string())
+                .unwrap_or_default();
+        }
+        Ok(network)
+    }
+
+    // Let the core find the interfaces, with the link type detected from the system
+    pub fn autodetect() -> LANScanAPINetwork {
+        detect_link(None).to_network()
+    }
+
+    // Lists are passed comma separated on the background process command line, "-" when empty
+    pub fn to_args(&self) -> Vec<String> {
+        [&self.interfaces, &self.cidrs, &self.exclude_cidrs]
+            .iter()
+            .map(|list| {
+                if list.is_empty() {
+                    "-".to_string()
+                } else {
+                    list.join(",")
+                }
+            })
+            .chain([self.ipv6.to_string()])
+            .collect()
+    }
+
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        if args.len() != 4 {
+            return None;
+        }
+        let list = |arg: &String| -> Vec<String> {
+            if arg == "-" {
+                vec![]
+            } else {
+                arg.split(',').map(|item| item.to_string()).collect()
+            }
+        };
+        Some(NetworkSelection {
+            interfaces: list(&args[0]),
+            cidrs: list(&args[1]),
+            exclude_cidrs: list(&args[2]),
+            ipv6: args[3] == "true",
+        })
+    }
+}
+
+// How the gateway of the scanned network is obtained
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GatewayOptions {
+    // Gateway supplied by the user, skips the detection
+    pub gateway: Option<String>,
+    // Seconds to wait for the gateway detection before scanning in degraded mode
+    pub timeout: u64,
+}
+
+impl Default for GatewayOptions {
+    fn default() -> Self {
+        GatewayOptions {
+            gateway: None,
+            timeout: 120,
+        }
+    }
+}
+
+impl GatewayOptions {
+    pub fn to_args(&self) -> Vec<String> {
+        vec![
+            self.gateway.clone().unwrap_or("-".to_string()),
+            self.timeout.to_string(),
+        ]
+    }
+
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        if args.len() != 2 {
+            return None;
+        }
+        Some(GatewayOptions {
+            gateway: match args[0].as_str() {
+                "-" => None,
+                gateway => Some(gateway.to_string()),
+            },
+            timeout: args[1].parse().ok()?,
+        })
+    }
+}
+
+pub enum GatewayOutcome {
+    Detected,
+    Manual(String),
+    // No gateway found in time, the subnets are scanned anyway, autodetected or selected
+    Degraded { autodetected: bool },
+}
+
+impl fmt::Display for GatewayOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GatewayOutcome::Detected => write!(f, "Gateway detection complete"),
+            GatewayOutcome::Manual(gateway) => {
+                write!(f, "Using manually supplied gateway {}", gateway)
+            }
+            GatewayOutcome::Degraded { autodetected } => write!(
+                f,
+                "Gateway detection timed out, scanning the {} without a gateway (degraded mode)",
+                if *autodetected {
+                    "autodetected interfaces"
+                } else {
+                    "selected subnets"
+                }
+            ),
+        }
+    }
+}
+
+// The core has a single set of link fields, named after Wi-Fi but used for every link type
+// (detect_link fills them for Ethernet as well), make them describe the link of the gateway
+fn set_manual_gateway(network: &mut LANScanAPINetwork, gateway: IpAddr) {
+    let interface = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|interface| match (&interface.addr, gateway) {
+            (if_addrs::IfAddr::V4(addr), IpAddr::V4(gateway)) => {
+                Ipv4Net::with_netmask(addr.ip, addr.netmask)
+                    .map(|net| net.contains(&gateway))
+                    .unwrap_or(false)
+            }
+            _ => false,
+        });
+    if let Some(interface) = interface {
+        let link = detect_link(Some(&interface.name));
+        *network = LANScanAPINetwork {
+            interfaces: network.interfaces.clone(),
+            scanned_interfaces: network.scanned_interfaces.clone(),
+            wifi_ipv6: network.wifi_ipv6.clone(),
+            ..link.to_network()
+        };
+    }
+    network.wifi_gateway = gateway.to_string();
+    // Without a link type the network looks disconnected, assume Ethernet
+    if !(network.is_ethernet || network.is_wifi || network.is_tethering || network.is_mobile) {
+        network.is_ethernet = true;
+    }
+}
+
+// Set the network, grant consent and wait for the gateway, ready for a scan request
+pub fn prepare_lan_scan(
+    selection: &NetworkSelection,
+    gateway: &GatewayOptions,
+) -> Result<GatewayOutcome, String> {
+    let mut network = selection.to_network()?;
+    if let Some(address) = &gateway.gateway {
+        let ip = address
+            .parse::<IpAddr>()
+            .map_err(|e| format!("Invalid gateway {}: {}", address, e))?;
+        set_manual_gateway(&mut network, ip);
+    }
+    set_network(network);
+
+    // Grant consent
+    grant_consent();
+
+    if let Some(address) = &gateway.gateway {
+        return Ok(GatewayOutcome::Manual(address.clone()));
+    }
+
+    // Wait for the gateway detection to complete, isolated networks may have none
+    let deadline = Instant::now() + Duration::from_secs(gateway.timeout);
+    loop {
+        if !get_last_gateway_scan().is_empty() {
+            return Ok(GatewayOutcome::Detected);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(GatewayOutcome::Degraded {
+                autodetected: selection.is_autodetected(),
+            });
+        }
+        info!("Waiting for gateway detection to complete...");
+        sleep(Duration::from_secs(2).min(deadline - now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(cidrs: &[&str]) -> Vec<Ipv4Net> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn carve() {
+        let cases: [(&str, &[&str], &[&str]); 6] = [
+            // Full exclusion, by the same or a larger range
+            ("10.0.0.0/24", &["10.0.0.0/24"], &[]),
+            ("10.0.0.0/24", &["10.0.0.0/16"], &[]),
+            // Partial exclusions
+            ("10.0.0.0/24", &["10.0.0.0/25"], &["10.0.0.128/25"]),
+            (
+                "10.0.0.0/24",
+                &["10.0.0.64/26"],
+                &["10.0.0.0/26", "10.0.0.128/25"],
+            ),
+            (
+                "10.0.0.0/24",
+                &["10.0.0.5/32", "10.0.0.128/25"],
+                &[
+                    "10.0.0.0/30",
+                    "10.0.0.4/32",
+                    "10.0.0.6/31",
+                    "10.0.0.8/29",
+                    "10.0.0.16/28",
+                    "10.0.0.32/27",
+                    "10.0.0.64/26",
+                ],
+            ),
+            // Excludes outside the subnet, the address is truncated to its network
+            (
+                "10.0.0.7/24",
+                &["10.0.1.0/24", "192.168.0.0/16"],
+                &["10.0.0.0/24"],
+            ),
+        ];
+        for (net, exclude, expected) in cases.iter() {
+            assert_eq!(
+                carve_cidrs(net.parse().unwrap(), &nets(exclude)),
+                nets(expected),
+                "{} without {:?}",
+                net,
+                exclude
+            );
+        }
+    }
+
+    #[test]
+    fn carved_pieces_cover_the_rest() {
+        let net: Ipv4Net = "192.168.1.0/24".parse().unwrap();
+        let exclude = nets(&["192.168.1.10/31", "192.168.1.200/29"]);
+        let pieces = carve_cidrs(net, &exclude);
+        for host in net.hosts() {
+            let excluded = exclude.iter().any(|cidr| cidr.contains(&host));
+            let covered = pieces.iter().filter(|piece| piece.contains(&host)).count();
+            assert_eq!(covered, if excluded { 0 } else { 1 }, "{}", host);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{describe_socket, LanDevice, LanInventory, ListeningSocket, MetadataReport};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+
+// Port given by number or by service name (e.g. "telnet", "microsoft-ds")
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum PortRule {
+    Number(u16),
+    Service(String),
+}
+
+impl PortRule {
+    fn matches(&self, port: u16, service: &str) -> bool {
+        match self {
+            PortRule::Number(number) => *number == port,
+            PortRule::Service(name) => name.eq_ignore_ascii_case(service),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct LanPolicy {
+    pub forbidden_ports: Vec<PortRule>,
+    pub forbidden_device_types: Vec<String>,
+    // Maximum number of devices with "High" criticality, unlimited if not set
+    pub max_high_criticality: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct MetadataPolicy {
+    // No cloud metadata endpoint answering without a token (AWS IMDSv1, GCP v1beta1)
+    pub forbid_unauthenticated: bool,
+    // No cloud metadata endpoint reachable at all
+    pub forbid_reachable: bool,
+}
+
+impl MetadataPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.forbid_unauthenticated || self.forbid_reachable
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct SocketPolicy {
+    // When set, listeners on other ports are unexpected unless their process is allowed
+    pub allowed_ports: Vec<PortRule>,
+    // Process names (as in /proc/<pid>/comm) allowed to listen on any port
+    pub allowed_processes: Vec<String>,
+    pub forbidden_ports: Vec<PortRule>,
+    // No listener bound to all interfaces, loopback only
+    pub forbid_all_interfaces: bool,
+}
+
+// Security policy file, in YAML
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Policy {
+    pub lan: LanPolicy,
+    pub metadata: MetadataPolicy,
+    pub sockets: SocketPolicy,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyViolation {
+    pub rule: String,
+    pub details: String,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}", self.rule, self.details)
+    }
+}
+
+pub fn load_policy(path: &str) -> Result<Policy, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Unable to read policy {}: {}", path, e))?;
+    serde_yaml::from_str(&contents).map_err(|e| format!("Unable to parse policy {}: {}", path, e))
+}
+
+fn describe_device(device: &LanDevice) -> String {
+    format!(
+        "'{}' ({}, {})",
+        device.hostname,
+        device.ips().join(", "),
+        device.macs().join(", ")
+    )
+}
+
+pub fn check_lan_policy(policy: &LanPolicy, inventory: &LanInventory) -> Vec<PolicyViolation> {
+    // An incomplete scan cannot prove that the policy holds
+    if inventory.last_scan.is_empty() {
+        return vec![PolicyViolation {
+            rule: "scan-incomplete".to_string(),
+            details: "the LAN scan did not complete".to_string(),
+        }];
+    }
+
+    let mut violations = Vec::new();
+
+    for device in inventory.devices.iter() {
+        for port in device.open_ports.iter() {
+            if policy
+                .forbidden_ports
+                .iter()
+                .any(|rule| rule.matches(port.port, &port.service))
+            {
+                violations.push(PolicyViolation {
+                    rule: "forbidden-port".to_string(),
+                    details: format!(
+                        "device {} has port {}/{} ({}) open",
+                        describe_device(device),
+                        port.port,
+                        port.protocol,
+                        port.service
+                    ),
+                });
+            }
+        }
+
+        if policy
+            .forbidden_device_types
+            .iter()
+            .any(|device_type| device_type.eq_ignore_ascii_case(&device.device_type))
+        {
+            violations.push(PolicyViolation {
+                rule: "forbidden-device-type".to_string(),
+                details: format!(
+                    "device {} is of forbidden type {}",
+                    describe_device(device),
+                    device.device_type
+                ),
+            });
+        }
+    }
+
+    if let Some(max) = policy.max_high_criticality {
+        let high: Vec<&LanDevice> = inventory
+            .devices
+            .iter()
+            .filter(|device| device.criticality == "High")
+            .collect();
+        if high.len() > max {
+            violations.push(PolicyViolation {
+                rule: "max-high-criticality".to_string(),
+                details: format!(
+                    "{} devices have High criticality (maximum {}): {}",
+                    high.len(),
+                    max,
+                    high.iter()
+                        .map(|device| describe_device(device))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+            });
+        }
+    }
+
+    violations
+}
+
+pub fn check_metadata_policy(
+    policy: &MetadataPolicy,
+    report: &MetadataReport,
+) -> Vec<PolicyViolation> {
+    let mut violations = Vec::new();
+    for endpoint in report
+        .endpoints
+        .iter()
+        .filter(|endpoint| endpoint.reachable)
+    {
+        if policy.forbid_unauthenticated && endpoint.unauthenticated {
+            violations.push(PolicyViolation {
+                rule: "metadata-unauthenticated".to_string(),
+                details: format!(
+                    "metadata endpoint {} at {} answers without a token",
+                    endpoint.endpoint, report.address
+                ),
+            });
+        } else if policy.forbid_reachable {
+            violations.push(PolicyViolation {
+                rule: "metadata-reachable".to_string(),
+                details: format!(
+                    "metadata endpoint {} at {} is reachable",
+                    endpoint.endpoint, report.address
+                ),
+            });
+        }
+    }
+    violations
+}
+
+pub fn check_socket_policy(
+    policy: &SocketPolicy,
+    sockets: &[ListeningSocket],
+) -> Vec<PolicyViolation> {
+    let mut violations = Vec::new();
+    for socket in sockets.iter() {
+        let allowed_process = policy.allowed_processes.contains(&socket.process);
+        if policy
+            .forbidden_ports
+            .iter()
+            .any(|rule| rule.matches(socket.port, &socket.service))
+        {
+            violations.push(PolicyViolation {
+                rule: "forbidden-listener".to_string(),
+                details: format!("{} listens on a forbidden port", describe_socket(socket)),
+            });
+        } else if (!policy.allowed_ports.is_empty() || !policy.allowed_processes.is_empty())
+            && !allowed_process
+            && !policy
+                .allowed_ports
+                .iter()
+                .any(|rule| rule.matches(socket.port, &socket.service))
+        {
+            violations.push(PolicyViolation {
+                rule: "unexpected-listener".to_string(),
+                details: format!(
+                    "{} is not in the allowed ports or processes",
+                    describe_socket(socket)
+                ),
+            });
+        }
+        if policy.forbid_all_interfaces && socket.all_interfaces && !allowed_process {
+            violations.push(PolicyViolation {
+                rule: "all-interfaces-listener".to_string(),
+                details: format!("{} is bound to all interfaces", describe_socket(socket)),
+            });
+        }
+    }
+    violations
+}
+
+// Print the violations, returns true if the policy is respected
+pub fn report_policy_violations(title: &str, violations: &[PolicyViolation]) -> bool {
+    println!("{}:", title);
+    if violations.is_empty() {
+        println!("  - No violation");
+    }
+    for violation in violations.iter() {
+        println!("  - {}", violation);
+    }
+    println!();
+    violations.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket(port: u16, service: &str) -> ListeningSocket {
+        ListeningSocket {
+            protocol: "tcp".to_string(),
+            address: "127.0.0.1".to_string(),
+            port,
+            service: service.to_string(),
+            all_interfaces: false,
+            user: "root".to_string(),
+            pid: None,
+            process: "daemon".to_string(),
+            exe: String::new(),
+        }
+    }
+
+    #[test]
+    fn port_rules() {
+        let policy: Policy =
+            serde_yaml::from_str("sockets:\n  forbidden_ports: [23, \"Microsoft-DS\", \"445\"]\n")
+                .unwrap();
+        assert_eq!(
+            policy.sockets.forbidden_ports,
+            vec![
+                PortRule::Number(23),
+                PortRule::Service("Microsoft-DS".to_string()),
+                // Quoted numbers are service names
+                PortRule::Service("445".to_string()),
+            ]
+        );
+
+        let cases = [
+            (23, "", true),
+            (2323, "telnet", false),
+            // Service names are matched regardless of case
+            (445, "microsoft-ds", true),
+            (139, "netbios-ssn", false),
+        ];
+        for (port, service, forbidden) in cases {
+            let violations = check_socket_policy(&policy.sockets, &[socket(port, service)]);
+            assert_eq!(!violations.is_empty(), forbidden, "{} {}", port, service);
+        }
+    }
+
+    #[test]
+    fn allowed_ports() {
+        let policy = SocketPolicy {
+            allowed_ports: vec![PortRule::Number(22), PortRule::Service("http".to_string())],
+            ..Default::default()
+        };
+        let sockets = [socket(22, "ssh"), socket(8080, "http"), socket(25, "smtp")];
+        let violations = check_socket_policy(&policy, &sockets);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].rule, "unexpected-listener");
+    }
+
+    #[test]
+    fn incomplete_scan() {
+        let policy = LanPolicy::default();
+        let mut inventory = LanInventory {
+            last_scan: String::new(),
+            gateway: String::new(),
+            interfaces: Vec::new(),
+            devices: Vec::new(),
+        };
+        let violations = check_lan_policy(&policy, &inventory);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].rule, "scan-incomplete");
+
+        inventory.last_scan = "2024-01-01T00:00:00Z".to_string();
+        assert!(check_lan_policy(&policy, &inventory).is_empty());
+    }
+}
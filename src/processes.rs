@@ -0,0 +1,341 @@
+use crate::State;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use sysinfo::{Pid, System, Users};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProcessRecord {
+    pub pid: u32,
+    pub parent_pid: Option<u32>,
+    pub name: String,
+    pub exe: String,
+    pub cmd: String,
+    // Effective user
+    pub user: String,
+    // Seconds since epoch, identifies the process along with its PID
+    pub start_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProcessSnapshot {
+    pub taken_at: DateTime<Utc>,
+    pub processes: Vec<ProcessRecord>,
+}
+
+// Process that appeared since the snapshot
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProcessDrift {
+    #[serde(flatten)]
+    pub process: ProcessRecord,
+    // running-as-root, temp-path, deleted-executable, detached
+    pub flags: Vec<String>,
+}
+
+pub fn default_process_snapshot_path() -> PathBuf {
+    dirs::home_dir()
+        .expect("Unable to find home directory")
+        .join(".edamame_posture_processes.json")
+}
+
+// Kernel threads are children of kthreadd (pid 2) and have neither an executable nor a command line
+fn is_kernel_thread(process: &ProcessRecord) -> bool {
+    process.pid == 2
+        || process.parent_pid == Some(2)
+        || (process.exe.is_empty() && process.cmd.is_empty())
+}
+
+pub fn current_processes(system: &System) -> Vec<ProcessRecord> {
+    let users = Users::new_with_refreshed_list();
+    let mut processes: Vec<ProcessRecord> = system
+        .processes()
+        .iter()
+        // Threads are listed as processes on Linux
+        .filter(|(_, process)| process.thread_kind().is_none())
+        .map(|(pid, process)| ProcessRecord {
+            pid: pid.as_u32(),
+            parent_pid: process.parent().map(|parent| parent.as_u32()),
+            name: process.name().to_string(),
+            exe: process
+                .exe()
+                .map(|exe| exe.display().to_string())
+                .unwrap_or_default(),
+            cmd: process.cmd().join(" "),
+            user: process
+                .effective_user_id()
+                .or(process.user_id())
+                .and_then(|uid| users.get_user_by_id(uid))
+                .map(|user| user.name().to_string())
+                .unwrap_or_default(),
+            start_time: process.start_time(),
+        })
+        .filter(|process| !is_kernel_thread(process))
+        .collect();
+    processes.sort_by_key(|process| process.pid);
+    processes
+}
+
+pub fn take_process_snapshot(path: &str) -> Result<ProcessSnapshot, String> {
+    let snapshot = ProcessSnapshot {
+        taken_at: Utc::now(),
+        processes: current_processes(&System::new_all()),
+    };
+    let contents = serde_json::to_string_pretty(&snapshot)
+        .map_err(|e| format!("Unable to serialize the process snapshot: {}", e))?;
+    fs::write(path, contents)
+        .map_err(|e| format!("Unable to write the process snapshot {}: {}", path, e))?;
+    Ok(snapshot)
+}
+
+pub fn load_process_snapshot(path: &str) -> Result<ProcessSnapshot, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read the process snapshot {}: {}", path, e))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| format!("Unable to parse the process snapshot {}: {}", path, e))
+}
+
+// World-writable locations where droppers usually stage their payloads
+fn is_temp_path(exe: &str) -> bool {
+    let mut prefixes = vec![
+        "/tmp/".to_string(),
+        "/var/tmp/".to_string(),
+        "/dev/shm/".to_string(),
+    ];
+    for variable in ["TMPDIR", "TEMP", "TMP", "RUNNER_TEMP"] {
+        if let Ok(dir) = std::env::var(variable) {
+            if !dir.is_empty() {
+                prefixes.push(format!("{}/", dir.trim_end_matches(['/', '\\'])));
+            }
+        }
+    }
+    prefixes
+        .iter()
+        .any(|prefix| exe.starts_with(prefix.as_str()))
+}
+
+// Our own process and its ancestors (the shell running the job step)
+fn own_lineage(system: &System) -> HashSet<u32> {
+    let mut lineage = HashSet::new();
+    let mut pid = Some(Pid::from_u32(std::process::id()));
+    while let Some(current) = pid {
+        if !lineage.insert(current.as_u32()) {
+            break;
+        }
+        pid = system.process(current).and_then(|process| process.parent());
+    }
+    lineage
+}
+
+// Not started by the job: none of its ancestors belongs to our lineage, init excepted.
+// Orphans are reparented to init or to a subreaper once their parent exits.
+fn is_detached(
+    process: &ProcessRecord,
+    parents: &HashMap<u32, Option<u32>>,
+    lineage: &HashSet<u32>,
+) -> bool {
+    let mut visited = HashSet::new();
+    let mut parent = process.parent_pid;
+    while let Some(pid) = parent {
+        if pid == 1 || !visited.insert(pid) {
+            break;
+        }
+        if lineage.contains(&pid) {
+            return false;
+        }
+        parent = parents.get(&pid).copied().flatten();
+    }
+    true
+}
+
+pub fn process_drift(snapshot: &ProcessSnapshot) -> Vec<ProcessDrift> {
+    let known: HashSet<(u32, u64)> = snapshot
+        .processes
+        .iter()
+        .map(|process| (process.pid, process.start_time))
+        .collect();
+    let system = System::new_all();
+    let lineage = own_lineage(&system);
+    let processes = current_processes(&system);
+    let parents: HashMap<u32, Option<u32>> = processes
+        .iter()
+        .map(|process| (process.pid, process.parent_pid))
+        .collect();
+    // Our background process is usually started after the snapshot
+    let state = State::load();
+    let is_daemon = |process: &ProcessRecord| {
+        state.pid == Some(process.pid)
+            && (state.start_time == 0 || state.start_time == process.start_time)
+    };
+    processes
+        .iter()
+        .filter(|process| {
+            !known.contains(&(process.pid, process.start_time))
+                && !lineage.contains(&process.pid)
+                && !is_daemon(process)
+        })
+        .cloned()
+        .map(|process| {
+            let mut flags = Vec::new();
+            if process.user == "root" || process.user == "SYSTEM" {
+                flags.push("running-as-root".to_string());
+            }
+            if is_temp_path(&process.exe) {
+                flags.push("temp-path".to_string());
+            }
+            if process.exe.ends_with(" (deleted)") {
+                flags.push("deleted-executable".to_string());
+            }
+            // It outlives the step that started it, or was started outside the job
+            if is_detached(&process, &parents, &lineage) {
+                flags.push("detached".to_string());
+            }
+            ProcessDrift { process, flags }
+        })
+        .collect()
+}
+
+pub fn handle_snapshot_processes(path: &str) {
+    match take_process_snapshot(path) {
+        Ok(snapshot) => println!(
+            "Process snapshot saved to {}: {} processes",
+            path,
+            snapshot.processes.len()
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    }
+}
+
+// Print the processes that appeared since the snapshot, returns them
+pub fn handle_drift_processes(path: &str, json: bool) -> Vec<ProcessDrift> {
+    let snapshot = match load_process_snapshot(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("{}", e);
+            // Exit with an error code
+            std::process::exit(1);
+        }
+    };
+    let drift = process_drift(&snapshot);
+    if json {
+        match serde_json::to_string_pretty(&drift) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Unable to serialize the process drift: {}", e),
+        }
+        return drift;
+    }
+    println!(
+        "Processes started since the snapshot of {}:",
+        snapshot.taken_at.to_rfc3339()
+    );
+    if drift.is_empty() {
+        println!("  - None");
+    }
+    for entry in drift.iter() {
+        let process = &entry.process;
+        println!(
+            "  - {} (pid {}, parent {}){}",
+            process.name,
+            process.pid,
+            process
+                .parent_pid
+                .map(|pid| pid.to_string())
+                .unwrap_or("none".to_string()),
+            if entry.flags.is_empty() {
+                "".to_string()
+            } else {
+                format!(" [{}]", entry.flags.join(", "))
+            }
+        );
+        println!("    - User: {}", process.user);
+        if !process.exe.is_empty() {
+            println!("    - Executable: {}", process.exe);
+        }
+        if !process.cmd.is_empty() {
+            println!("    - Command: {}", process.cmd);
+        }
+    }
+    println!(
+        "Total: {} new process(es), {} flagged",
+        drift.len(),
+        drift.iter().filter(|entry| !entry.flags.is_empty()).count()
+    );
+    println!();
+    drift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, parent_pid: Option<u32>, exe: &str, cmd: &str) -> ProcessRecord {
+        ProcessRecord {
+            pid,
+            parent_pid,
+            name: String::new(),
+            exe: exe.to_string(),
+            cmd: cmd.to_string(),
+            user: String::new(),
+            start_time: 0,
+        }
+    }
+
+    #[test]
+    fn kernel_threads() {
+        assert!(is_kernel_thread(&process(2, Some(0), "", "")));
+        assert!(is_kernel_thread(&process(40, Some(2), "", "")));
+        assert!(is_kernel_thread(&process(40, Some(1), "", "")));
+        assert!(!is_kernel_thread(&process(
+            40,
+            Some(1),
+            "/usr/bin/sleep",
+            "sleep 1"
+        )));
+        // Executable of another user, not readable
+        assert!(!is_kernel_thread(&process(40, Some(1), "", "sleep 1")));
+    }
+
+    #[test]
+    fn detached() {
+        // init (1) -> runner (10) -> step shell (20) -> drift (30), init -> sshd (50)
+        let lineage: HashSet<u32> = [1, 10, 20, 30].into_iter().collect();
+        let parents: HashMap<u32, Option<u32>> = [
+            (1, None),
+            (10, Some(1)),
+            (20, Some(10)),
+            (30, Some(20)),
+            (50, Some(1)),
+            (60, Some(10)),
+            // Loop, as in a snapshot taken while PIDs are reused
+            (70, Some(71)),
+            (71, Some(70)),
+        ]
+        .into_iter()
+        .collect();
+        let cases = [
+            // Started by the job, directly or through a new process
+            (Some(20), false),
+            (Some(60), false),
+            // Reparented to init, or started by a service outside the job
+            (Some(1), true),
+            (Some(50), true),
+            (None, true),
+            // Parent already gone
+            (Some(99), true),
+            (Some(70), true),
+        ];
+        for (parent_pid, detached) in cases {
+            assert_eq!(
+                is_detached(&process(100, parent_pid, "", ""), &parents, &lineage),
+                detached,
+                "{:?}",
+                parent_pid
+            );
+        }
+    }
+}
@@ -0,0 +1,624 @@
+use crate::{
+    apply_device_database, discover_ipv6_neighbors, print_lan_inventory, wait_for_lanscan,
+    Ipv6Neighbor, LanDevice, LanDisplayOptions, LanInterface, LanInventory, LanPort,
+    NetworkSelection,
+};
+use edamame_core::api::api_lanscan::get_lan_devices;
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Probes used by the light profile to find hosts when no port range is given, the
+// hosts on the local subnets that answer none of them are still found by the ARP
+// resolution of the probes
+const DISCOVERY_PORTS: [u16; 3] = [22, 80, 443];
+// Largest number of addresses the light profile sweeps
+const MAX_SWEEP_HOSTS: usize = 65536;
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+const PROBE_WORKERS: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanProfile {
+    // Neighbor table and already known devices only, no packet sent
+    Passive,
+    // Rate limited TCP connect sweep of the selected subnets
+    Light,
+    // Full scan by the core, with port scans of every device
+    Full,
+}
+
+impl ScanProfile {
+    pub fn parse(profile: &str) -> Option<Self> {
+        match profile {
+            "passive" => Some(ScanProfile::Passive),
+            "light" => Some(ScanProfile::Light),
+            "full" => Some(ScanProfile::Full),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScanProfile::Passive => "passive",
+            ScanProfile::Light => "light",
+            ScanProfile::Full => "full",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ScanOptions {
+    pub profile: ScanProfile,
+    // Probes per second of the light profile
+    pub max_pps: u32,
+    // Seconds before giving up on the scan and reporting partial results, 0 for no limit
+    pub max_duration: u64,
+    // Ports probed by the light profile, e.g. "22,80,8000-8100"
+    pub ports: Option<String>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            profile: ScanProfile::Full,
+            max_pps: 50,
+            max_duration: 0,
+            ports: None,
+        }
+    }
+}
+
+pub fn parse_ports(ports: &str) -> Result<Vec<u16>, String> {
+    let mut parsed = Vec::new();
+    for item in ports.split(',').map(|item| item.trim()) {
+        let invalid = || format!("Invalid port or port range: {}", item);
+        match item.split_once('-') {
+            Some((start, end)) => {
+                let start: u16 = start.parse().map_err(|_| invalid())?;
+                let end: u16 = end.parse().map_err(|_| invalid())?;
+                if start == 0 || start > end {
+                    return Err(invalid());
+                }
+                parsed.extend(start..=end);
+            }
+            None => {
+                let port: u16 = item.parse().map_err(|_| invalid())?;
+                if port == 0 {
+                    return Err(invalid());
+                }
+                parsed.push(port);
+            }
+        }
+    }
+    parsed.sort();
+    parsed.dedup();
+    Ok(parsed)
+}
+
+impl ScanOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(ports) = &self.ports {
+            parse_ports(ports)?;
+        }
+        if self.max_pps == 0 {
+            return Err("The packets per second limit must be positive".to_string());
+        }
+        // The core scanner has no rate or port controls
+        if self.profile == ScanProfile::Full && self.ports.is_some() {
+            return Err(
+                "A port range can only be used with the light profile, the full profile scans the ports chosen by the core"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    fn max_wait(&self) -> Option<u64> {
+        match self.max_duration {
+            0 => None,
+            max_duration => Some(max_duration),
+        }
+    }
+
+    pub fn to_args(&self) -> Vec<String> {
+        vec![
+            self.profile.name().to_string(),
+            self.max_pps.to_string(),
+            self.max_duration.to_string(),
+            self.ports.clone().unwrap_or("-".to_string()),
+        ]
+    }
+
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        if args.len() != 4 {
+            return None;
+        }
+        Some(ScanOptions {
+            profile: ScanProfile::parse(&args[0])?,
+            max_pps: args[1].parse().ok()?,
+            max_duration: args[2].parse().ok()?,
+            ports: match args[3].as_str() {
+                "-" => None,
+                ports => Some(ports.to_string()),
+            },
+        })
+    }
+}
+
+// Service names reported by the light profile
+pub const SERVICE_NAMES: [(u16, &str); 20] = [
+    (21, "ftp"),
+    (22, "ssh"),
+    (23, "telnet"),
+    (25, "smtp"),
+    (53, "domain"),
+    (80, "http"),
+    (110, "pop3"),
+    (139, "netbios-ssn"),
+    (143, "imap"),
+    (443, "https"),
+    (445, "microsoft-ds"),
+    (1433, "ms-sql-s"),
+    (3306, "mysql"),
+    (3389, "ms-wbt-server"),
+    (5432, "postgresql"),
+    (5900, "vnc"),
+    (6379, "redis"),
+    (8080, "http-proxy"),
+    (9200, "elasticsearch"),
+    (27017, "mongodb"),
+];
+
+pub fn service_name(port: u16) -> &'static str {
+    SERVICE_NAMES
+        .iter()
+        .find(|(known, _)| *known == port)
+        .map(|(_, name)| *name)
+        .unwrap_or("")
+}
+
+// IPv4 neighbors known to the system, as (ip, mac)
+pub fn neighbor_table() -> Vec<(Ipv4Addr, String)> {
+    #[cfg(target_os = "linux")]
+    {
+        let contents = std::fs::read_to_string("/proc/net/arp").unwrap_or_default();
+        contents
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                // Flags 0x0 are incomplete entries
+                if fields.len() < 4 || fields[2] == "0x0" || fields[3] == "00:00:00:00:00:00" {
+                    return None;
+                }
+                Some((fields[0].parse().ok()?, fields[3].to_lowercase()))
+            })
+            .collect()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        use std::process::Command;
+
+        // "arp -a" formats differ between macOS and Windows, look for an IP and a MAC on each line
+        let output = match Command::new("arp").arg("-an").output() {
+            Ok(output) if output.status.success() => output,
+            _ => match Command::new("arp").arg("-a").output() {
+                Ok(output) => output,
+                Err(_) => return vec![],
+            },
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let tokens: Vec<String> = line
+                    .split_whitespace()
+                    .map(|token| token.trim_matches(|c| c == '(' || c == ')').to_string())
+                    .collect();
+                let ip = tokens
+                    .iter()
+                    .find_map(|token| token.parse::<Ipv4Addr>().ok())?;
+                let mac = tokens.iter().find(|token| {
+                    let parts: Vec<&str> = token.split([':', '-']).collect();
+                    parts.len() == 6
+                        && parts
+                            .iter()
+                            .all(|part| u8::from_str_radix(part, 16).is_ok())
+                })?;
+                Some((ip, mac.replace('-', ":").to_lowercase()))
+            })
+            .collect()
+    }
+}
+
+fn subnets_to_sweep(selection: &NetworkSelection) -> Result<Vec<(Ipv4Addr, u32)>, String> {
+    // Use the selection, or the interfaces autodetected by the core
+    let interfaces = if selection.is_empty() {
+        get_lan_devices(false, false, false)
+            .network
+            .network
+            .interfaces
+    } else {
+        selection.scan_interfaces()?
+    };
+    if interfaces.is_empty() {
+        return Err("No interface to sweep, select one with --interface or --cidr".to_string());
+    }
+    Ok(interfaces
+        .iter()
+        .filter_map(|(ip, prefix, _)| Some((ip.parse().ok()?, *prefix)))
+        .collect())
+}
+
+fn sweep_hosts(
+    subnets: &[(Ipv4Addr, u32)],
+    exclude_cidrs: &[String],
+) -> Result<Vec<Ipv4Addr>, String> {
+    let exclude_cidrs: Vec<Ipv4Net> = exclude_cidrs
+        .iter()
+        .filter_map(|cidr| cidr.parse().ok())
+        .collect();
+    // Subnets carved by --exclude-cidr are pieces of a local subnet, only its own network and broadcast addresses are skipped
+    let local_addresses: Vec<(Ipv4Addr, Ipv4Net)> = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|interface| match &interface.addr {
+            if_addrs::IfAddr::V4(addr) => Ipv4Net::with_netmask(addr.ip, addr.netmask)
+                .ok()
+                .map(|net| (addr.ip, net.trunc())),
+            _ => None,
+        })
+        .collect();
+    let local_nets: Vec<Ipv4Net> = local_addresses
+        .iter()
+        .map(|(_, net)| *net)
+        .filter(|net| net.prefix_len() < 31)
+        .collect();
+    let mut hosts = BTreeSet::new();
+    for (ip, prefix) in subnets.iter() {
+        let net = Ipv4Net::new(*ip, *prefix as u8)
+            .map_err(|e| format!("Invalid subnet {}/{}: {}", ip, prefix, e))?;
+        let in_local_net = local_nets.iter().any(|local| local.contains(&net));
+        for host in net.trunc().hosts() {
+            hosts.insert(host);
+            if hosts.len() > MAX_SWEEP_HOSTS {
+                return Err(format!(
+                    "Too many addresses to sweep (more than {}), select smaller subnets with --cidr",
+                    MAX_SWEEP_HOSTS
+                ));
+            }
+        }
+        if in_local_net && net.prefix_len() < 31 {
+            hosts.insert(net.network());
+            hosts.insert(net.broadcast());
+        }
+    }
+    hosts.retain(|host| {
+        !local_addresses.iter().any(|(ip, _)| ip == host)
+            && !exclude_cidrs.iter().any(|cidr| cidr.contains(host))
+            && !local_nets
+                .iter()
+                .any(|net| *host == net.network() || *host == net.broadcast())
+    });
+    Ok(hosts.into_iter().collect())
+}
+
+// Keep the neighbors in the subnets, if any, and out of the excluded CIDRs
+fn selected_neighbors(
+    neighbors: Vec<(Ipv4Addr, String)>,
+    subnets: Option<&[(Ipv4Addr, u32)]>,
+    selection: &NetworkSelection,
+) -> Vec<(Ipv4Addr, String)> {
+    let nets: Option<Vec<Ipv4Net>> = subnets.map(|subnets| {
+        subnets
+            .iter()
+            .filter_map(|(ip, prefix)| Ipv4Net::new(*ip, *prefix as u8).ok())
+            .map(|net| net.trunc())
+            .collect()
+    });
+    let exclude_cidrs: Vec<Ipv4Net> = selection
+        .exclude_cidrs
+        .iter()
+        .filter_map(|cidr| cidr.parse().ok())
+        .collect();
+    neighbors
+        .into_iter()
+        .filter(|(ip, _)| {
+            let included = match nets.as_ref() {
+                Some(nets) => nets.iter().any(|net| net.contains(ip)),
+                None => true,
+            };
+            included && !exclude_cidrs.iter().any(|cidr| cidr.contains(ip))
+        })
+        .collect()
+}
+
+// TCP connect probes at most max_pps per second, returns the hosts that answered with their open ports
+fn sweep(
+    hosts: &[Ipv4Addr],
+    ports: &[u16],
+    max_pps: u32,
+    deadline: Option<Instant>,
+) -> BTreeMap<Ipv4Addr, Vec<u16>> {
+    let (job_tx, job_rx) = channel::<SocketAddr>();
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (result_tx, result_rx) = channel::<(Ipv4Addr, u16, bool)>();
+
+    let workers: Vec<_> = (0..PROBE_WORKERS)
+        .map(|_| {
+            let job_rx = job_rx.clone();
+            let result_tx = result_tx.clone();
+            std::thread::spawn(move || loop {
+                let job = job_rx.lock().map(|rx| rx.recv());
+                let address = match job {
+                    Ok(Ok(address)) => address,
+                    _ => break,
+                };
+                let ip = match address.ip() {
+                    IpAddr::V4(ip) => ip,
+                    IpAddr::V6(_) => continue,
+                };
+                match TcpStream::connect_timeout(&address, PROBE_TIMEOUT) {
+                    Ok(_) => {
+                        let _ = result_tx.send((ip, address.port(), true));
+                    }
+                    // A reset means the host is up with the port closed
+                    Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                        let _ = result_tx.send((ip, address.port(), false));
+                    }
+                    Err(_) => {}
+                }
+            })
+        })
+        .collect();
+    drop(result_tx);
+
+    let interval = Duration::from_secs_f64(1.0 / max_pps as f64);
+    let mut next = Instant::now();
+    'dispatch: for host in hosts.iter() {
+        for port in ports.iter() {
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                info!("LAN sweep stopped at the maximum scan duration");
+                break 'dispatch;
+            }
+            let now = Instant::now();
+            if next > now {
+                sleep(next - now);
+            }
+            next += interval;
+            if job_tx
+                .send(SocketAddr::new(IpAddr::V4(*host), *port))
+                .is_err()
+            {
+                break 'dispatch;
+            }
+        }
+    }
+    drop(job_tx);
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    let mut responders: BTreeMap<Ipv4Addr, Vec<u16>> = BTreeMap::new();
+    for (ip, port, open) in result_rx.iter() {
+        let ports = responders.entry(ip).or_default();
+        if open {
+            ports.push(port);
+            ports.sort();
+        }
+    }
+    responders
+}
+
+fn neighbor_device(ip: String, mac: Option<String>, open_ports: &[u16]) -> LanDevice {
+    LanDevice {
+        hostname: "".to_string(),
+        custom_name: "".to_string(),
+        ip_address: ip.clone(),
+        ip_addresses: vec![ip],
+        mac_address: mac.clone().unwrap_or_default(),
+        mac_addresses: mac.into_iter().collect(),
+        mdns_services: vec![],
+        os_name: "".to_string(),
+        os_version: "".to_string(),
+        device_vendor: "".to_string(),
+        device_type: "".to_string(),
+        // Not assessed by the core
+        criticality: "Unknown".to_string(),
+        has_edamame: false,
+        edamame_version: "".to_string(),
+        open_ports: open_ports
+            .iter()
+            .map(|port| LanPort {
+                port: *port,
+                protocol: "tcp".to_string(),
+                service: service_name(*port).to_string(),
+                banner: "".to_string(),
+                vulnerabilities: vec![],
+            })
+            .collect(),
+        dismissed_ports: vec![],
+        vulnerabilities: vec![],
+        active: true,
+        added: false,
+        activated: false,
+        deactivated: false,
+        deleted: false,
+        no_icmp: false,
+        non_std_ports: false,
+        last_time_seen: chrono::Utc::now().to_rfc3339(),
+    }
+}
+
+// Wait for a new core scan, the scan may not have started yet when we first look
+fn full_scan(options: &ScanOptions) -> LanInventory {
+    let before = get_lan_devices(false, false, false).last_scan;
+    _ = get_lan_devices(true, false, false);
+    let deadline = options
+        .max_wait()
+        .map(|max_wait| Instant::now() + Duration::from_secs(max_wait));
+    let start_deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let devices = get_lan_devices(false, false, false);
+        if devices.scan_in_progress
+            || devices.last_scan != before
+            || Instant::now() >= start_deadline
+        {
+            break;
+        }
+        sleep(Duration::from_secs(1));
+    }
+    let max_wait =
+        deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()).as_secs());
+    LanInventory::from(&wait_for_lanscan(true, max_wait))
+}
+
+fn neighbor_scan(
+    selection: &NetworkSelection,
+    options: &ScanOptions,
+) -> Result<LanInventory, String> {
+    // Devices already known to the core, without requesting a scan
+    let known = get_lan_devices(false, false, false);
+    let mut inventory = LanInventory::from(&known);
+
+    // The subnets are only needed to sweep or to filter the neighbors of a selection
+    let subnets = if options.profile == ScanProfile::Light || !selection.is_empty() {
+        Some(subnets_to_sweep(selection)?)
+    } else {
+        None
+    };
+
+    let mut responders = BTreeMap::new();
+    if let (ScanProfile::Light, Some(subnets)) = (options.profile, &subnets) {
+        let hosts = sweep_hosts(subnets, &selection.exclude_cidrs)?;
+        let ports = match &options.ports {
+            Some(ports) => parse_ports(ports)?,
+            None => DISCOVERY_PORTS.to_vec(),
+        };
+        println!(
+            "Sweeping {} addresses on {} ports at {} probes per second...",
+            hosts.len(),
+            ports.len(),
+            options.max_pps
+        );
+        let deadline = options
+            .max_wait()
+            .map(|max_wait| Instant::now() + Duration::from_secs(max_wait));
+        responders = sweep(&hosts, &ports, options.max_pps, deadline);
+    }
+
+    // Merge the responders and the neighbors of the selected subnets with the known devices
+    let neighbors = selected_neighbors(neighbor_table(), subnets.as_deref(), selection);
+    let mut addresses: BTreeMap<Ipv4Addr, Vec<u16>> = responders;
+    for (ip, _) in neighbors.iter() {
+        addresses.entry(*ip).or_default();
+    }
+    for (ip, open_ports) in addresses.iter() {
+        let ip_string = ip.to_string();
+        let mac = neighbors
+            .iter()
+            .find(|(neighbor, _)| neighbor == ip)
+            .map(|(_, mac)| mac.clone());
+        let known = inventory.devices.iter_mut().find(|device| {
+            device.ips().contains(&ip_string)
+                || matches!(&mac, Some(mac) if device.macs().contains(mac))
+        });
+        match known {
+            Some(device) => {
+                for port in open_ports.iter() {
+                    if !device.open_ports.iter().any(|p| p.port == *port) {
+                        device.open_ports.push(LanPort {
+                            port: *port,
+                            protocol: "tcp".to_string(),
+                            service: service_name(*port).to_string(),
+                            banner: "".to_string(),
+                            vulnerabilities: vec![],
+                        });
+                    }
+                }
+            }
+            None => inventory
+                .devices
+                .push(neighbor_device(ip_string, mac, open_ports)),
+        }
+    }
+    inventory.last_scan = chrono::Utc::now().to_rfc3339();
+    Ok(inventory)
+}
+
+// Add the IPv6 neighbors to the devices with the same MAC address, or as new devices
+fn merge_ipv6_neighbors(inventory: &mut LanInventory, neighbors: &[Ipv6Neighbor]) {
+    for neighbor in neighbors.iter() {
+        let ip = neighbor.ip.to_string();
+        let known = inventory
+            .devices
+            .iter_mut()
+            .find(|device| device.macs().contains(&neighbor.mac) || device.ips().contains(&ip));
+        match known {
+            Some(device) => {
+                if !device.ip_addresses.contains(&ip) {
+                    device.ip_addresses.push(ip);
+                }
+            }
+            None => inventory
+                .devices
+                .push(neighbor_device(ip, Some(neighbor.mac.clone()), &[])),
+        }
+    }
+}
+
+// Scan the LAN with the given profile, the network must have been prepared
+pub fn run_lan_scan(
+    selection: &NetworkSelection,
+    options: &ScanOptions,
+    display: &LanDisplayOptions,
+) -> Result<LanInventory, String> {
+    options.validate()?;
+    let subnets6 = if selection.ipv6 {
+        selection.scan_interfaces6()?
+    } else {
+        vec![]
+    };
+    println!("LAN scan profile: {}", options.profile.name());
+    let mut inventory = match options.profile {
+        ScanProfile::Full => full_scan(options),
+        ScanProfile::Passive | ScanProfile::Light => neighbor_scan(selection, options)?,
+    };
+    if inventory.last_scan.is_empty() {
+        println!("LAN scan not completed");
+        return Ok(inventory);
+    }
+
+    if selection.ipv6 {
+        println!(
+            "IPv6 subnets: {}",
+            subnets6
+                .iter()
+                .map(|(ip, prefix, name)| format!("{} ({}/{})", name, ip, prefix))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        // The passive profile only reads the NDP table
+        let neighbors = discover_ipv6_neighbors(&subnets6, options.profile != ScanProfile::Passive);
+        println!("IPv6 neighbors: {}", neighbors.len());
+        merge_ipv6_neighbors(&mut inventory, &neighbors);
+        for (ip, prefix, name) in subnets6.iter() {
+            inventory.interfaces.push(LanInterface {
+                name: name.clone(),
+                ip: ip.to_string(),
+                prefix: *prefix as u32,
+            });
+        }
+    }
+
+    let inventory = apply_device_database(inventory);
+    print_lan_inventory(&inventory, display);
+    Ok(inventory)
+}
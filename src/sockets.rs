@@ -0,0 +1,312 @@
+use crate::service_name;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use sysinfo::{Uid, Users};
+
+// Listening socket of the local host, with its owning process when it could be resolved
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ListeningSocket {
+    // tcp, tcp6, udp or udp6
+    pub protocol: String,
+    pub address: String,
+    pub port: u16,
+    pub service: String,
+    // Bound to 0.0.0.0 or ::
+    pub all_interfaces: bool,
+    pub user: String,
+    // Processes of other users are only visible to root
+    pub pid: Option<u32>,
+    pub process: String,
+    pub exe: String,
+}
+
+// Socket states of /proc/net, TCP_CLOSE is the state of unconnected UDP sockets
+pub const TCP_ESTABLISHED: &str = "01";
+pub const TCP_SYN_SENT: &str = "02";
+pub const TCP_CLOSE: &str = "07";
+pub const TCP_LISTEN: &str = "0A";
+
+// Entry of /proc/net/tcp, tcp6, udp or udp6
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcNetEntry {
+    pub local_address: String,
+    pub local_port: u16,
+    pub remote_address: String,
+    pub remote_port: u16,
+    pub state: String,
+    pub uid: u32,
+    pub inode: u64,
+}
+
+// Addresses are hex encoded 32 bits host words, whose bytes are in network order
+fn parse_address(hex: &str) -> Option<String> {
+    match hex.len() {
+        8 => {
+            let word = u32::from_str_radix(hex, 16).ok()?;
+            Some(Ipv4Addr::from(word.to_ne_bytes()).to_string())
+        }
+        32 => {
+            let mut bytes = [0u8; 16];
+            for (index, chunk) in bytes.chunks_mut(4).enumerate() {
+                let word = u32::from_str_radix(&hex[index * 8..index * 8 + 8], 16).ok()?;
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            Some(Ipv6Addr::from(bytes).to_string())
+        }
+        _ => None,
+    }
+}
+
+fn parse_endpoint(endpoint: &str) -> Option<(String, u16)> {
+    let (address, port) = endpoint.split_once(':')?;
+    Some((parse_address(address)?, u16::from_str_radix(port, 16).ok()?))
+}
+
+fn parse_proc_net(contents: &str) -> Vec<ProcNetEntry> {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                return None;
+            }
+            let (local_address, local_port) = parse_endpoint(fields[1])?;
+            let (remote_address, remote_port) = parse_endpoint(fields[2])?;
+            Some(ProcNetEntry {
+                local_address,
+                local_port,
+                remote_address,
+                remote_port,
+                state: fields[3].to_string(),
+                uid: fields[7].parse().ok()?,
+                inode: fields[9].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+// Sockets of tcp, tcp6, udp or udp6, the IPv6 tables are missing when IPv6 is disabled
+pub fn proc_net_entries(protocol: &str) -> Vec<ProcNetEntry> {
+    match fs::read_to_string(format!("/proc/net/{}", protocol)) {
+        Ok(contents) => parse_proc_net(&contents),
+        Err(_) => vec![],
+    }
+}
+
+// Socket inode to owning PID, from the file descriptors of the processes
+pub fn socket_owners() -> HashMap<u64, u32> {
+    let mut owners = HashMap::new();
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return owners,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let pid = match entry.file_name().to_string_lossy().parse::<u32>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.filter_map(|fd| fd.ok()) {
+            if let Ok(target) = fs::read_link(fd.path()) {
+                if let Some(inode) = target
+                    .to_string_lossy()
+                    .strip_prefix("socket:[")
+                    .and_then(|rest| rest.strip_suffix(']'))
+                    .and_then(|inode| inode.parse::<u64>().ok())
+                {
+                    owners.entry(inode).or_insert(pid);
+                }
+            }
+        }
+    }
+    owners
+}
+
+// Name and executable path of a process
+pub fn process_identity(pid: u32) -> (String, String) {
+    (
+        fs::read_to_string(format!("/proc/{}/comm", pid))
+            .map(|comm| comm.trim().to_string())
+            .unwrap_or_default(),
+        fs::read_link(format!("/proc/{}/exe", pid))
+            .map(|exe| exe.display().to_string())
+            .unwrap_or_default(),
+    )
+}
+
+pub fn listening_sockets() -> Result<Vec<ListeningSocket>, String> {
+    if !cfg!(target_os = "linux") {
+        return Err("The listening socket audit is only supported on Linux".to_string());
+    }
+    let owners = socket_owners();
+    let users = Users::new_with_refreshed_list();
+    let mut sockets = Vec::new();
+    for protocol in ["tcp", "tcp6", "udp", "udp6"] {
+        let udp = protocol.starts_with("udp");
+        for entry in proc_net_entries(protocol) {
+            let listening = if udp {
+                entry.state == TCP_CLOSE && entry.remote_port == 0
+            } else {
+                entry.state == TCP_LISTEN
+            };
+            if !listening {
+                continue;
+            }
+            let pid = owners.get(&entry.inode).copied();
+            let user = entry
+                .uid
+                .to_string()
+                .parse::<Uid>()
+                .ok()
+                .and_then(|uid| users.get_user_by_id(&uid))
+                .map(|user| user.name().to_string())
+                .unwrap_or(entry.uid.to_string());
+            let (process, exe) = match pid {
+                Some(pid) => process_identity(pid),
+                None => ("".to_string(), "".to_string()),
+            };
+            sockets.push(ListeningSocket {
+                protocol: protocol.to_string(),
+                all_interfaces: entry.local_address == "0.0.0.0" || entry.local_address == "::",
+                address: entry.local_address,
+                port: entry.local_port,
+                service: service_name(entry.local_port).to_string(),
+                user,
+                pid,
+                process,
+                exe,
+            });
+        }
+    }
+    sockets.sort_by(|a, b| (a.port, &a.protocol).cmp(&(b.port, &b.protocol)));
+    sockets.dedup();
+    Ok(sockets)
+}
+
+pub fn describe_socket(socket: &ListeningSocket) -> String {
+    let address = if socket.protocol.ends_with('6') {
+        format!("[{}]:{}", socket.address, socket.port)
+    } else {
+        format!("{}:{}", socket.address, socket.port)
+    };
+    let process = match socket.pid {
+        Some(pid) => format!("{} (pid {})", socket.process, pid),
+        None => "unknown process".to_string(),
+    };
+    format!("{} {} by {}", socket.protocol, address, process)
+}
+
+pub fn print_listening_sockets(sockets: &[ListeningSocket]) {
+    println!("Listening sockets:");
+    if sockets.is_empty() {
+        println!("  - None");
+    }
+    for socket in sockets.iter() {
+        println!(
+            "  - {}{}{}",
+            describe_socket(socket),
+            if socket.service.is_empty() {
+                "".to_string()
+            } else {
+                format!(", service: {}", socket.service)
+            },
+            if socket.all_interfaces {
+                ", bound to all interfaces"
+            } else {
+                ""
+            }
+        );
+        println!("    - User: {}", socket.user);
+        if !socket.exe.is_empty() {
+            println!("    - Executable: {}", socket.exe);
+        }
+    }
+    println!();
+    let unresolved = sockets.iter().filter(|socket| socket.pid.is_none()).count();
+    if unresolved > 0 {
+        println!(
+            "{} socket(s) without a resolved process, processes of other users are only visible to root",
+            unresolved
+        );
+        println!();
+    }
+}
+
+// The samples are /proc/net lines of a little-endian host
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_addresses() {
+        let cases = [
+            ("0100007F", Some("127.0.0.1")),
+            ("00000000", Some("0.0.0.0")),
+            ("0A01A8C0", Some("192.168.1.10")),
+            ("00000000000000000000000001000000", Some("::1")),
+            ("000080FE000000000000000001000000", Some("fe80::1")),
+            (
+                "B80D01200000000000FF000029834200",
+                Some("2001:db8::ff00:42:8329"),
+            ),
+            ("0000000000000000FFFF00000100000A", Some("::ffff:10.0.0.1")),
+            ("0100007", None),
+            ("ZZ00007F", None),
+        ];
+        for (hex, expected) in cases.iter() {
+            assert_eq!(parse_address(hex).as_deref(), *expected, "{}", hex);
+        }
+    }
+
+    #[test]
+    fn parse_entries() {
+        let contents = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0035 00000000:0000 0A 00000000:00000000 00:00000000 00000000   101        0 12345 1 0000000000000000 100 0 0 10 0
+   1: 0A01A8C0:D2F0 08080808:01BB 01 00000000:00000000 02:000A7D2A 00000000  1000        0 67890 2 0000000000000000 20 4 30 10 -1
+   2: truncated line
+";
+        let entries = parse_proc_net(contents);
+        assert_eq!(
+            entries,
+            vec![
+                ProcNetEntry {
+                    local_address: "127.0.0.1".to_string(),
+                    local_port: 53,
+                    remote_address: "0.0.0.0".to_string(),
+                    remote_port: 0,
+                    state: TCP_LISTEN.to_string(),
+                    uid: 101,
+                    inode: 12345,
+                },
+                ProcNetEntry {
+                    local_address: "192.168.1.10".to_string(),
+                    local_port: 54000,
+                    remote_address: "8.8.8.8".to_string(),
+                    remote_port: 443,
+                    state: TCP_ESTABLISHED.to_string(),
+                    uid: 1000,
+                    inode: 67890,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_tcp6_entries() {
+        let contents = "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000000000000000000001000000:1F90 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 4242 1 0000000000000000 100 0 0 10 0
+";
+        let entries = parse_proc_net(contents);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].local_address, "::1");
+        assert_eq!(entries[0].local_port, 8080);
+        assert_eq!(entries[0].remote_address, "::");
+        assert_eq!(entries[0].inode, 4242);
+    }
+}
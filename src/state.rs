@@ -1,10 +1,15 @@
+use crate::{GatewayOptions, NetworkSelection, ScanOptions};
 use fs2::FileExt;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Distinguishes the temporary files of the threads of a process
+static SAVE_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct State {
@@ -14,53 +19,213 @@ pub struct State {
     pub connected_domain: String,
     pub connected_user: String,
     pub last_network_activity: String,
+    // Start time of the background process (seconds since epoch), used to detect PID reuse
+    #[serde(default)]
+    pub start_time: u64,
+    // Launch parameters, kept to allow a restart, except the PIN which is never written to disk
+    #[serde(default)]
+    pub device_id: String,
+    #[serde(default)]
+    pub lan_scanning: bool,
+    #[serde(default)]
+    pub max_duration: u64,
+    #[serde(default)]
+    pub parent_pid: Option<u32>,
+    #[serde(default)]
+    pub network: NetworkSelection,
+    #[serde(default)]
+    pub gateway: GatewayOptions,
+    // Outcome of the gateway detection of the background process
+    #[serde(default)]
+    pub gateway_outcome: String,
+    #[serde(default = "default_lan_monitor_interval")]
+    pub lan_monitor_interval: u64,
+    #[serde(default)]
+    pub scan: ScanOptions,
+    #[serde(default)]
+    pub egress_interval: u64,
+    #[serde(default)]
+    pub memory_watch: bool,
+    #[serde(default)]
+    pub backend_error_code: String,
+    // Completion date of the last LAN scan of the background process
+    #[serde(default)]
+    pub last_lan_scan: String,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            pid: None,
+            handle: None, // Initialize handle
+            is_success: false,
+            connected_domain: "".to_string(),
+            connected_user: "".to_string(),
+            last_network_activity: "".to_string(),
+            start_time: 0,
+            device_id: "".to_string(),
+            lan_scanning: false,
+            max_duration: 0,
+            parent_pid: None,
+            network: NetworkSelection::default(),
+            gateway: GatewayOptions::default(),
+            gateway_outcome: "".to_string(),
+            lan_monitor_interval: DEFAULT_LAN_MONITOR_INTERVAL,
+            scan: ScanOptions::default(),
+            egress_interval: 0,
+            memory_watch: false,
+            backend_error_code: "".to_string(),
+            last_lan_scan: "".to_string(),
+        }
+    }
+}
+
+// Same default as the start command
+pub const DEFAULT_LAN_MONITOR_INTERVAL: u64 = 300;
+
+fn default_lan_monitor_interval() -> u64 {
+    DEFAULT_LAN_MONITOR_INTERVAL
+}
+
+// Exclusive lock held by the background process for its whole lifetime
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    pub fn acquire() -> Option<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(Self::lock_file_path())
+            .expect("Unable to open lock file");
+        match file.try_lock_exclusive() {
+            Ok(_) => Some(InstanceLock { _file: file }),
+            Err(_) => None,
+        }
+    }
+
+    pub fn is_held() -> bool {
+        // If we can take the lock, nobody else holds it, release it immediately
+        match Self::acquire() {
+            Some(lock) => {
+                drop(lock);
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn lock_file_path() -> PathBuf {
+        dirs::home_dir()
+            .expect("Unable to find home directory")
+            .join(".edamame_posture.lock")
+    }
 }
 
 impl State {
     pub fn load() -> Self {
-        let path = Self::state_file_path();
-        if path.exists() {
-            let mut file = File::open(&path).expect("Unable to open state file");
-            file.lock_shared().expect("Unable to lock file for reading");
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)
-                .expect("Unable to read state file");
-            let state: State = serde_yaml::from_str(&contents).expect("Unable to parse state file");
-            file.unlock().expect("Unable to unlock file");
-            state
-        } else {
-            State {
-                pid: None,
-                handle: None, // Initialize handle
-                is_success: false,
-                connected_domain: "".to_string(),
-                connected_user: "".to_string(),
-                last_network_activity: "".to_string(),
+        Self::read().unwrap_or_default()
+    }
+
+    // None when there is no state, the file is replaced by a rename so a read is never partial
+    fn read() -> Option<Self> {
+        match fs::read_to_string(Self::state_file_path()) {
+            Ok(contents) => match serde_yaml::from_str(&contents) {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    eprintln!("Unable to parse state file: {}", e);
+                    Some(State::default())
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                eprintln!("Unable to read state file: {}", e);
+                Some(State::default())
             }
         }
     }
 
+    // Change the state of the running background process, unless stop cleared it: returns false then
+    pub fn update(modify: impl FnOnce(&mut State)) -> bool {
+        let mut state = match Self::read() {
+            Some(state) => state,
+            None => return false,
+        };
+        modify(&mut state);
+        // Check again right before writing, to keep the window with stop small
+        if !Self::state_file_path().exists() {
+            return false;
+        }
+        state.save();
+        true
+    }
+
     pub fn save(&self) {
         let path = Self::state_file_path();
-        let mut file = File::create(&path).expect("Unable to create state file");
-        file.lock_exclusive()
-            .expect("Unable to lock file for writing");
+        // Write to a temporary file and rename it, so that readers woken up by the change never see a partial file
+        // Each writer has its own temporary file, concurrent writers can't mix their contents
+        let tmp_path = path.with_extension(format!(
+            "yaml.{}.{}.tmp",
+            std::process::id(),
+            SAVE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = Self::create_private_file(&tmp_path).expect("Unable to create state file");
         let contents = serde_yaml::to_string(self).expect("Unable to serialize state");
         file.write_all(contents.as_bytes())
             .expect("Unable to write state file");
-        file.unlock().expect("Unable to unlock file");
+        drop(file);
+        fs::rename(&tmp_path, &path).expect("Unable to replace state file");
     }
 
-    fn state_file_path() -> PathBuf {
+    // The state holds the launch parameters, only the user can read it
+    fn create_private_file(path: &PathBuf) -> std::io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)
+    }
+
+    pub fn state_file_path() -> PathBuf {
         dirs::home_dir()
             .expect("Unable to find home directory")
             .join(".edamame_posture.yaml")
     }
 
+    // PID of the background process spawned by start, known before it writes the state
+    fn launch_file_path() -> PathBuf {
+        dirs::home_dir()
+            .expect("Unable to find home directory")
+            .join(".edamame_posture.launch")
+    }
+
+    pub fn save_launched_pid(pid: u32) {
+        if let Err(e) = fs::write(Self::launch_file_path(), pid.to_string()) {
+            eprintln!("Unable to write launch file: {}", e);
+        }
+    }
+
+    pub fn launched_pid() -> Option<u32> {
+        fs::read_to_string(Self::launch_file_path())
+            .ok()
+            .and_then(|pid| pid.trim().parse().ok())
+    }
+
+    pub fn clear_launched_pid() {
+        let _ = fs::remove_file(Self::launch_file_path());
+    }
+
     pub fn clear() {
-        let path = Self::state_file_path();
-        if path.exists() {
-            fs::remove_file(path).expect("Unable to delete state file");
+        match fs::remove_file(Self::state_file_path()) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                eprintln!("Unable to delete state file: {}", e)
+            }
+            _ => {}
         }
     }
 }
@@ -0,0 +1,399 @@
+use crate::{LanDevice, LanInventory};
+use ipnet::IpNet;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::net::IpAddr;
+use sysinfo::System;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    JsonGraph,
+}
+
+impl GraphFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "dot" => Some(GraphFormat::Dot),
+            "mermaid" => Some(GraphFormat::Mermaid),
+            "json-graph" => Some(GraphFormat::JsonGraph),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NodeKind {
+    Host,
+    Subnet,
+    Gateway,
+    Device,
+}
+
+impl NodeKind {
+    fn name(&self) -> &'static str {
+        match self {
+            NodeKind::Host => "host",
+            NodeKind::Subnet => "subnet",
+            NodeKind::Gateway => "gateway",
+            NodeKind::Device => "device",
+        }
+    }
+}
+
+struct Node {
+    id: String,
+    kind: NodeKind,
+    label: String,
+    ips: Vec<String>,
+    macs: Vec<String>,
+    vendor: String,
+    // Only set for scanned devices, including a gateway found by the scan
+    criticality: Option<String>,
+    has_edamame: bool,
+}
+
+struct Edge {
+    source: String,
+    target: String,
+    label: String,
+}
+
+struct Topology {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+fn device_name(device: &LanDevice) -> String {
+    [&device.custom_name, &device.hostname, &device.device_vendor]
+        .iter()
+        .find(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| device.ips().first().cloned().unwrap_or_default())
+}
+
+fn device_node(id: String, kind: NodeKind, device: &LanDevice) -> Node {
+    Node {
+        id,
+        kind,
+        label: device_name(device),
+        ips: device.ips(),
+        macs: device.macs(),
+        vendor: device.device_vendor.clone(),
+        criticality: Some(device.criticality.clone()),
+        has_edamame: device.has_edamame,
+    }
+}
+
+// Host, subnets, gateway and devices, devices hang off the subnet that contains them
+fn build_topology(inventory: &LanInventory) -> Topology {
+    let mut nodes = Vec::new();
+    let mut edges = Vec::new();
+
+    let local_ips: Vec<String> = inventory.interfaces.iter().map(|i| i.ip.clone()).collect();
+    nodes.push(Node {
+        id: "host".to_string(),
+        kind: NodeKind::Host,
+        label: System::host_name().unwrap_or("this host".to_string()),
+        ips: local_ips.clone(),
+        macs: vec![],
+        vendor: "".to_string(),
+        criticality: None,
+        has_edamame: true,
+    });
+
+    let mut subnets: Vec<(String, IpNet)> = Vec::new();
+    for interface in inventory.interfaces.iter() {
+        let net = match interface
+            .ip
+            .parse::<IpAddr>()
+            .ok()
+            .and_then(|ip| IpNet::new(ip, interface.prefix as u8).ok())
+        {
+            Some(net) => net.trunc(),
+            None => continue,
+        };
+        let subnet_id = match subnets.iter().find(|(_, known)| *known == net) {
+            Some((id, _)) => id.clone(),
+            None => {
+                let id = format!("subnet_{}", subnets.len());
+                nodes.push(Node {
+                    id: id.clone(),
+                    kind: NodeKind::Subnet,
+                    label: net.to_string(),
+                    ips: vec![],
+                    macs: vec![],
+                    vendor: "".to_string(),
+                    criticality: None,
+                    has_edamame: false,
+                });
+                subnets.push((id.clone(), net));
+                id
+            }
+        };
+        edges.push(Edge {
+            source: "host".to_string(),
+            target: subnet_id,
+            label: format!("{} ({})", interface.name, interface.ip),
+        });
+    }
+
+    let subnet_of = |ips: &[String]| -> Option<String> {
+        ips.iter()
+            .filter_map(|ip| ip.parse::<IpAddr>().ok())
+            .find_map(|ip| {
+                subnets
+                    .iter()
+                    .find(|(_, net)| net.contains(&ip))
+                    .map(|(id, _)| id.clone())
+            })
+    };
+
+    // The gateway is usually among the scanned devices
+    let gateway_device = inventory.devices.iter().position(|device| {
+        !inventory.gateway.is_empty() && device.ips().contains(&inventory.gateway)
+    });
+    if !inventory.gateway.is_empty() {
+        let node = match gateway_device {
+            Some(index) => device_node(
+                "gateway".to_string(),
+                NodeKind::Gateway,
+                &inventory.devices[index],
+            ),
+            None => Node {
+                id: "gateway".to_string(),
+                kind: NodeKind::Gateway,
+                label: "gateway".to_string(),
+                ips: vec![inventory.gateway.clone()],
+                macs: vec![],
+                vendor: "".to_string(),
+                criticality: None,
+                has_edamame: false,
+            },
+        };
+        let source = subnet_of(&node.ips).unwrap_or("host".to_string());
+        edges.push(Edge {
+            source,
+            target: "gateway".to_string(),
+            label: "gateway".to_string(),
+        });
+        nodes.push(node);
+    }
+
+    for (index, device) in inventory.devices.iter().enumerate() {
+        if Some(index) == gateway_device {
+            continue;
+        }
+        let ips = device.ips();
+        // The scan may list our own host
+        if ips.iter().any(|ip| local_ips.contains(ip)) {
+            continue;
+        }
+        let id = format!("device_{}", index);
+        // Devices outside the scanned subnets are reached through the gateway
+        let source = subnet_of(&ips).unwrap_or_else(|| {
+            if inventory.gateway.is_empty() {
+                "host".to_string()
+            } else {
+                "gateway".to_string()
+            }
+        });
+        edges.push(Edge {
+            source,
+            target: id.clone(),
+            label: "".to_string(),
+        });
+        nodes.push(device_node(id, NodeKind::Device, device));
+    }
+
+    Topology { nodes, edges }
+}
+
+fn node_details(node: &Node) -> Vec<String> {
+    let mut details = vec![node.label.clone()];
+    if !node.ips.is_empty() && !node.ips.contains(&node.label) {
+        details.push(node.ips.join(", "));
+    }
+    if let Some(criticality) = &node.criticality {
+        details.push(format!("criticality: {}", criticality));
+        if node.has_edamame {
+            details.push("EDAMAME".to_string());
+        }
+    }
+    details
+}
+
+fn dot_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn criticality_color(criticality: &str) -> &'static str {
+    match criticality {
+        "High" => "#f4a3a3",
+        "Medium" => "#f9d49c",
+        "Low" => "#b9e4b0",
+        _ => "#e0e0e0",
+    }
+}
+
+fn to_dot(topology: &Topology) -> String {
+    let mut dot = String::from("graph lan {\n    node [style=filled, fontname=\"Helvetica\"];\n");
+    for node in topology.nodes.iter() {
+        let label = node_details(node)
+            .iter()
+            .map(|detail| dot_escape(detail))
+            .collect::<Vec<String>>()
+            .join("\\n");
+        let shape = match node.kind {
+            NodeKind::Host => "doubleoctagon",
+            NodeKind::Subnet => "ellipse",
+            NodeKind::Gateway => "diamond",
+            NodeKind::Device => "box",
+        };
+        let color = match (&node.criticality, node.kind) {
+            (Some(criticality), _) => criticality_color(criticality),
+            (None, NodeKind::Host) => "#9cc3f9",
+            (None, NodeKind::Subnet) => "#ffffff",
+            _ => "#e0e0e0",
+        };
+        // EDAMAME devices get a thick border
+        let penwidth = if node.has_edamame && node.kind != NodeKind::Host {
+            3
+        } else {
+            1
+        };
+        dot.push_str(&format!(
+            "    \"{}\" [label=\"{}\", shape={}, fillcolor=\"{}\", penwidth={}];\n",
+            node.id, label, shape, color, penwidth
+        ));
+    }
+    for edge in topology.edges.iter() {
+        dot.push_str(&format!(
+            "    \"{}\" -- \"{}\" [label=\"{}\"];\n",
+            edge.source,
+            edge.target,
+            dot_escape(&edge.label)
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn mermaid_escape(value: &str) -> String {
+    value.replace('"', "#quot;")
+}
+
+fn to_mermaid(topology: &Topology) -> String {
+    let mut mermaid = String::from("graph TD\n");
+    for node in topology.nodes.iter() {
+        let label = node_details(node)
+            .iter()
+            .map(|detail| mermaid_escape(detail))
+            .collect::<Vec<String>>()
+            .join("<br/>");
+        let line = match node.kind {
+            NodeKind::Host => format!("    {}[[\"{}\"]]\n", node.id, label),
+            NodeKind::Subnet => format!("    {}([\"{}\"])\n", node.id, label),
+            NodeKind::Gateway => format!("    {}{{\"{}\"}}\n", node.id, label),
+            NodeKind::Device => format!("    {}[\"{}\"]\n", node.id, label),
+        };
+        mermaid.push_str(&line);
+        if let Some(criticality) = &node.criticality {
+            let class = match criticality.as_str() {
+                "High" | "Medium" | "Low" => criticality.to_lowercase(),
+                _ => "unknown".to_string(),
+            };
+            mermaid.push_str(&format!("    class {} {}\n", node.id, class));
+        }
+        if node.has_edamame && node.kind != NodeKind::Host {
+            mermaid.push_str(&format!("    class {} edamame\n", node.id));
+        }
+    }
+    for edge in topology.edges.iter() {
+        if edge.label.is_empty() {
+            mermaid.push_str(&format!("    {} --- {}\n", edge.source, edge.target));
+        } else {
+            mermaid.push_str(&format!(
+                "    {} ---|\"{}\"| {}\n",
+                edge.source,
+                mermaid_escape(&edge.label),
+                edge.target
+            ));
+        }
+    }
+    for class in ["high", "medium", "low", "unknown"] {
+        let color = criticality_color(&(class[..1].to_uppercase() + &class[1..]));
+        mermaid.push_str(&format!("    classDef {} fill:{}\n", class, color));
+    }
+    mermaid.push_str("    classDef edamame stroke-width:3px\n");
+    mermaid
+}
+
+// JSON Graph Format (https://jsongraphformat.info)
+fn to_json_graph(topology: &Topology, inventory: &LanInventory) -> String {
+    let mut nodes = Map::new();
+    for node in topology.nodes.iter() {
+        let mut metadata = json!({
+            "type": node.kind.name(),
+            "ips": node.ips,
+            "macs": node.macs,
+            "vendor": node.vendor,
+            "has_edamame": node.has_edamame,
+        });
+        if let Some(criticality) = &node.criticality {
+            metadata["criticality"] = json!(criticality);
+        }
+        nodes.insert(
+            node.id.clone(),
+            json!({ "label": node.label, "metadata": metadata }),
+        );
+    }
+    let edges: Vec<Value> = topology
+        .edges
+        .iter()
+        .map(|edge| {
+            let mut value = json!({ "source": edge.source, "target": edge.target });
+            if !edge.label.is_empty() {
+                value["label"] = json!(edge.label);
+            }
+            value
+        })
+        .collect();
+    let graph = json!({
+        "graph": {
+            "label": "LAN topology",
+            "directed": false,
+            "metadata": { "last_scan": inventory.last_scan, "gateway": inventory.gateway },
+            "nodes": nodes,
+            "edges": edges,
+        }
+    });
+    serde_json::to_string_pretty(&graph).unwrap_or_default()
+}
+
+pub fn lan_topology(inventory: &LanInventory, format: GraphFormat) -> String {
+    let topology = build_topology(inventory);
+    match format {
+        GraphFormat::Dot => to_dot(&topology),
+        GraphFormat::Mermaid => to_mermaid(&topology),
+        GraphFormat::JsonGraph => to_json_graph(&topology, inventory),
+    }
+}
+
+// Write the topology to the file, or to stdout if none is given
+pub fn export_lan_topology(
+    inventory: &LanInventory,
+    format: GraphFormat,
+    path: Option<&String>,
+) -> Result<(), String> {
+    let graph = lan_topology(inventory, format);
+    match path {
+        Some(path) => fs::write(path, graph)
+            .map_err(|e| format!("Unable to write LAN topology to {}: {}", path, e)),
+        None => {
+            println!("{}", graph);
+            Ok(())
+        }
+    }
+}
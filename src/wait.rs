@@ -0,0 +1,246 @@
+use crate::{pid_exists, State};
+use notify::{recommended_watcher, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+// A backend error reported for this long is not transient (e.g. an invalid PIN or domain)
+const BACKEND_ERROR_GRACE: Duration = Duration::from_secs(30);
+
+// Steps of the background process startup, in the order they complete: the LAN is scanned before the connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WaitPhase {
+    Daemon,
+    LanScan,
+    Connection,
+    Report,
+}
+
+impl fmt::Display for WaitPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            WaitPhase::Daemon => "background process to be alive",
+            WaitPhase::LanScan => "LAN scan to complete",
+            WaitPhase::Connection => "domain connection",
+            WaitPhase::Report => "first report to be sent",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+enum PhaseStatus {
+    Done,
+    Pending,
+    Failed(String),
+}
+
+// Wakes up the waiters as soon as the background process writes the state file
+struct StateWatcher {
+    // None if notifications are not available, we then rely on polling only
+    _watcher: Option<RecommendedWatcher>,
+    rx: Receiver<()>,
+}
+
+impl StateWatcher {
+    fn new() -> Self {
+        let (tx, rx) = channel();
+        let path = State::state_file_path();
+        let watcher = recommended_watcher(move |res: notify::Result<Event>| {
+            if let Ok(event) = res {
+                if event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == path.file_name())
+                {
+                    let _ = tx.send(());
+                }
+            }
+        })
+        .and_then(|mut watcher| {
+            // Watch the directory as the state file is removed and recreated
+            let dir = State::state_file_path()
+                .parent()
+                .map(|dir| dir.to_path_buf())
+                .unwrap_or_default();
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+        StateWatcher {
+            _watcher: watcher.ok(),
+            rx,
+        }
+    }
+
+    // Return on the next change of the state file or after the timeout
+    fn wait(&self, timeout: Duration) {
+        if self.rx.recv_timeout(timeout).is_ok() {
+            // Coalesce the burst of events of a single write
+            while self.rx.try_recv().is_ok() {}
+        }
+    }
+}
+
+pub struct WaitOptions {
+    // Overall timeout in seconds, bounds all the phases
+    pub timeout: u64,
+    // Maximum seconds between two reads of the state, changes are otherwise notified
+    pub poll_interval: u64,
+    // Per phase timeouts in seconds, default to the remaining overall timeout
+    pub daemon_timeout: Option<u64>,
+    pub connection_timeout: Option<u64>,
+    pub report_timeout: Option<u64>,
+    pub lanscan_timeout: Option<u64>,
+    // Fail as soon as the process spawned by start exits before writing the state
+    pub watch_launch: bool,
+}
+
+impl WaitOptions {
+    pub fn new(timeout: u64) -> Self {
+        WaitOptions {
+            timeout,
+            poll_interval: 5,
+            daemon_timeout: None,
+            connection_timeout: None,
+            report_timeout: None,
+            lanscan_timeout: None,
+            watch_launch: false,
+        }
+    }
+
+    fn phase_timeout(&self, phase: WaitPhase) -> Option<u64> {
+        match phase {
+            WaitPhase::Daemon => self.daemon_timeout,
+            WaitPhase::Connection => self.connection_timeout,
+            WaitPhase::Report => self.report_timeout,
+            WaitPhase::LanScan => self.lanscan_timeout,
+        }
+    }
+}
+
+fn backend_error(state: &State) -> Option<&String> {
+    Some(&state.backend_error_code).filter(|code| !code.is_empty() && *code != "None")
+}
+
+fn check_phase(phase: WaitPhase, state: &State, options: &WaitOptions) -> PhaseStatus {
+    // Past the first phase, the background process must stay alive
+    match state.pid {
+        Some(pid) if !pid_exists(pid, state.start_time) => {
+            return PhaseStatus::Failed(format!("background process ({}) is not running", pid))
+        }
+        None if phase != WaitPhase::Daemon => {
+            return PhaseStatus::Failed("background process has stopped".to_string())
+        }
+        _ => {}
+    }
+
+    match phase {
+        WaitPhase::Daemon => match state.pid {
+            Some(_) => PhaseStatus::Done,
+            // The state is written by the background process once launched, unless it fails first
+            None => match State::launched_pid() {
+                Some(pid) if options.watch_launch && !pid_exists(pid, 0) => PhaseStatus::Failed(
+                    format!("background process ({}) exited before starting", pid),
+                ),
+                _ => PhaseStatus::Pending,
+            },
+        },
+        // Backend errors can be transient, persistent ones are handled by the caller
+        WaitPhase::Connection => {
+            if state.is_success {
+                PhaseStatus::Done
+            } else {
+                PhaseStatus::Pending
+            }
+        }
+        WaitPhase::Report => {
+            if !state.last_network_activity.is_empty() {
+                PhaseStatus::Done
+            } else {
+                PhaseStatus::Pending
+            }
+        }
+        WaitPhase::LanScan => {
+            // Nothing to wait for if LAN scanning is disabled
+            if !state.lan_scanning || !state.last_lan_scan.is_empty() {
+                PhaseStatus::Done
+            } else {
+                PhaseStatus::Pending
+            }
+        }
+    }
+}
+
+// Wait for each phase in turn, the error names the phase that failed or timed out
+pub fn wait_for_phases(phases: &[WaitPhase], options: &WaitOptions) -> Result<State, String> {
+    let overall_deadline = Instant::now() + Duration::from_secs(options.timeout);
+    let poll_interval = Duration::from_secs(options.poll_interval.max(1));
+    let watcher = StateWatcher::new();
+    let mut state = State::load();
+
+    for phase in phases {
+        let started = Instant::now();
+        let deadline = match options.phase_timeout(*phase) {
+            Some(timeout) => overall_deadline.min(started + Duration::from_secs(timeout)),
+            None => overall_deadline,
+        };
+        println!("Waiting for {}...", phase);
+        // Backend error of the connection and when it was first seen
+        let mut last_error: Option<(String, Instant)> = None;
+        loop {
+            if *phase == WaitPhase::Connection && !state.is_success {
+                last_error = match (backend_error(&state), last_error) {
+                    (Some(code), Some((last, since))) if *code == last => {
+                        if since.elapsed() >= BACKEND_ERROR_GRACE {
+                            return Err(format!(
+                                "Failed waiting for {}: backend error {} for {}s",
+                                phase,
+                                code,
+                                since.elapsed().as_secs()
+                            ));
+                        }
+                        Some((last, since))
+                    }
+                    (Some(code), _) => Some((code.clone(), Instant::now())),
+                    (None, _) => None,
+                };
+            }
+            match check_phase(*phase, &state, options) {
+                PhaseStatus::Done => {
+                    println!(
+                        "Done waiting for {} ({}s)",
+                        phase,
+                        started.elapsed().as_secs()
+                    );
+                    break;
+                }
+                PhaseStatus::Failed(reason) => {
+                    return Err(format!("Failed waiting for {}: {}", phase, reason));
+                }
+                PhaseStatus::Pending => {}
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                let last_error = match backend_error(&state) {
+                    Some(code) if *phase == WaitPhase::Connection => {
+                        format!(", last backend error: {}", code)
+                    }
+                    _ => "".to_string(),
+                };
+                return Err(format!(
+                    "Timeout waiting for {} after {}s{}",
+                    phase,
+                    started.elapsed().as_secs(),
+                    last_error
+                ));
+            }
+            // Wake up in time to check a persistent backend error
+            let mut wait = poll_interval.min(deadline - now);
+            if let Some((_, since)) = &last_error {
+                wait = wait.min(BACKEND_ERROR_GRACE.saturating_sub(since.elapsed()));
+            }
+            watcher.wait(wait.max(Duration::from_millis(100)));
+            state = State::load();
+        }
+    }
+    Ok(state)
+}